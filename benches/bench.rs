@@ -39,6 +39,8 @@ fn count_zeros(arr: &[usize]) -> isize {
     count
 }
 
+// Benchmarks run on the toolchain criterion requires, which has `black_box`.
+#[allow(clippy::incompatible_msrv)]
 fn bench_zeroes(c: &mut Criterion) {
     // ----- setup -----
     let all_zeros: Vec<usize> = vec![0; 100_000_000];
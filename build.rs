@@ -1,23 +1,542 @@
 use rustc_version::{version_meta, Channel, Version};
+use std::env;
 
 fn main() {
-    match version_meta() {
-        Ok(meta) => {
-            if meta.channel == Channel::Nightly {
-                println!("cargo:rustc-cfg=branches_nightly");
+    println!("cargo:rerun-if-env-changed=BRANCHES_BACKEND");
+    let meta = version_meta().ok();
+    let at_least = |version: &str| {
+        meta.as_ref()
+            .map_or(false, |m| m.semver >= Version::parse(version).unwrap())
+    };
+
+    // Inline assembly support per architecture follows the compiler version.
+    if at_least("1.60.0") {
+        println!("cargo:rustc-cfg=rustc_ge_1_60_0");
+    }
+    if at_least("1.72.0") {
+        println!("cargo:rustc-cfg=rustc_ge_1_72_0");
+    }
+    if at_least("1.84.0") {
+        println!("cargo:rustc-cfg=rustc_ge_1_84_0");
+    }
+
+    // Library APIs are probed, since a nightly may rename or remove them
+    // before or after the release that stabilizes them. Without a usable
+    // compiler to probe with, fall back to the version they were stabilized.
+    let probe = Probe::new();
+    let has = |name: &str, code: &str, since: &str| match &probe {
+        Some(probe) => probe.compiles(name, code),
+        None => at_least(since),
+    };
+    if has(
+        "assert_unchecked",
+        "pub unsafe fn f(b: bool) { core::hint::assert_unchecked(b) }",
+        "1.81.0",
+    ) {
+        println!("cargo:rustc-cfg=branches_assert_unchecked");
+    }
+    if has(
+        "select_unpredictable",
+        "pub fn f(b: bool) -> u8 { core::hint::select_unpredictable(b, 1, 2) }",
+        "1.88.0",
+    ) {
+        println!("cargo:rustc-cfg=branches_select_unpredictable");
+    }
+    if has(
+        "cold_path",
+        "pub fn f() { core::hint::cold_path() }",
+        "1.95.0",
+    ) {
+        println!("cargo:rustc-cfg=branches_cold_path");
+    }
+
+    let nightly_channel = meta.as_ref().map_or(false, |m| {
+        m.channel == Channel::Nightly || m.channel == Channel::Dev
+    });
+    let intrinsics = || match &probe {
+        Some(probe) => probe.compiles("intrinsics", NIGHTLY_PROBE),
+        None => nightly_channel,
+    };
+    let codegen = codegen_backend();
+    println!("cargo:rustc-cfg=branches_codegen=\"{}\"", codegen);
+
+    let backend = env::var("BRANCHES_BACKEND").unwrap_or_default();
+    let nightly = match backend.as_str() {
+        // Cranelift ignores the branch weight intrinsics, but still moves
+        // the calls to the stable backend's cold function out of line.
+        "" | "auto" if codegen == "cranelift" => false,
+        "" | "auto" => {
+            let nightly = nightly_channel && intrinsics();
+            if nightly_channel && !nightly {
+                println!(
+                    "cargo:warning=the core intrinsics used by branches do not compile with \
+                     this nightly, falling back to the stable backend"
+                );
+            }
+            nightly
+        }
+        "stable" | "noop" => false,
+        "nightly" => {
+            if !intrinsics() {
+                panic!(
+                    "BRANCHES_BACKEND=nightly, but the core intrinsics used by branches do \
+                     not compile with this rustc (a nightly toolchain or RUSTC_BOOTSTRAP=1 is \
+                     required)"
+                );
+            }
+            true
+        }
+        other => panic!(
+            "unknown BRANCHES_BACKEND {:?}, expected stable, nightly, noop or auto",
+            other
+        ),
+    };
+    // If the compiler cannot be detected at all, the stable backend is the
+    // conservative choice: emitting no backend cfg would make the crate fail
+    // to compile with confusing type errors.
+    if nightly {
+        println!("cargo:rustc-cfg=branches_nightly");
+    } else {
+        println!("cargo:rustc-cfg=branches_stable");
+    }
+
+    let arch = env::var("CARGO_CFG_TARGET_ARCH").unwrap_or_default();
+    if arch == "arm" {
+        arm_prefetch_cfgs();
+    }
+
+    // Miri cannot run inline assembly, so every architecture-specific path
+    // takes its portable fallback. Under Miri or a sanitizer, an `assume`
+    // that does not hold is reported instead of being exploited, and
+    // prefetches are dropped as they have nothing to contribute there.
+    let miri = env::var_os("CARGO_CFG_MIRI").is_some();
+    // Cranelift only supports inline assembly on some targets, and through
+    // an external assembler, so it gets the portable paths as well.
+    let no_asm = miri || codegen == "cranelift";
+    if no_asm {
+        println!("cargo:rustc-cfg=branches_no_asm");
+    }
+    let checked = miri || env::var_os("CARGO_CFG_SANITIZE").is_some();
+
+    kill_switch_cfgs(backend == "noop", checked, no_asm);
+
+    // The `detect` feature only changes code generation on x86 targets that
+    // have the SSE prefetch instructions to fall back on.
+    if env::var_os("CARGO_FEATURE_DETECT").is_some()
+        && (arch == "x86" || arch == "x86_64")
+        && target_features().iter().any(|f| f == "sse")
+    {
+        println!("cargo:rustc-cfg=branches_x86_detect");
+    }
+
+    write_profile();
+}
+
+// Neutralizes whole categories of hints, so that the same code can be
+// measured with and without them. The `noop` backend turns all of them off,
+// `checked` builds default to checking `assume` without prefetching, and
+// prefetches are dropped as well when inline assembly is unavailable.
+fn kill_switch_cfgs(all: bool, checked: bool, no_asm: bool) {
+    for var in [
+        "BRANCHES_NO_HINTS",
+        "BRANCHES_NO_ASSUME",
+        "BRANCHES_NO_PREFETCH",
+    ]
+    .iter()
+    {
+        println!("cargo:rerun-if-env-changed={}", var);
+    }
+    let value = |var: &str| {
+        env::var(var)
+            .ok()
+            .filter(|v| !v.is_empty() && v != "0" && v != "false")
+            .or_else(|| if all { Some("1".to_string()) } else { None })
+    };
+    if value("BRANCHES_NO_HINTS").is_some() {
+        println!("cargo:rustc-cfg=branches_no_hints");
+    }
+    match value("BRANCHES_NO_ASSUME").as_deref() {
+        Some("check") => println!("cargo:rustc-cfg=branches_assume_check"),
+        _ if checked => println!("cargo:rustc-cfg=branches_assume_check"),
+        Some(_) => println!("cargo:rustc-cfg=branches_no_assume"),
+        None => {}
+    }
+    if checked || no_asm || value("BRANCHES_NO_PREFETCH").is_some() {
+        println!("cargo:rustc-cfg=branches_no_prefetch");
+    }
+}
+
+// Returns the codegen backend the crate is compiled with: `llvm`,
+// `cranelift` or `gcc`. Cargo's `codegen-backend` profile setting does not
+// show up in RUSTFLAGS, so `BRANCHES_CODEGEN_BACKEND` can name it instead.
+fn codegen_backend() -> String {
+    println!("cargo:rerun-if-env-changed=BRANCHES_CODEGEN_BACKEND");
+    match env::var("BRANCHES_CODEGEN_BACKEND").as_deref() {
+        Ok("") | Err(_) => {}
+        Ok(name @ "llvm") | Ok(name @ "cranelift") | Ok(name @ "gcc") => return name.to_string(),
+        Ok(other) => panic!(
+            "unknown BRANCHES_CODEGEN_BACKEND {:?}, expected llvm, cranelift or gcc",
+            other
+        ),
+    }
+    // The flag names a built-in backend or the path of a backend library,
+    // such as `librustc_codegen_cranelift.so`.
+    match unstable_flags("codegen-backend").pop() {
+        Some(name) if name.contains("cranelift") => "cranelift".to_string(),
+        Some(name) if name.contains("gcc") => "gcc".to_string(),
+        _ => "llvm".to_string(),
+    }
+}
+
+// Every intrinsic the nightly backend uses, with the signatures it relies on.
+const NIGHTLY_PROBE: &str = "
+#![feature(core_intrinsics)]
+#![allow(internal_features)]
+pub unsafe fn f(b: bool, p: *const u8) -> bool {
+    core::intrinsics::assume(b);
+    core::intrinsics::prefetch_read_data::<_, 3>(p);
+    core::intrinsics::prefetch_write_data::<_, 3>(p);
+    if b {
+        core::intrinsics::abort();
+    }
+    core::intrinsics::select_unpredictable(b, true, false)
+        && core::intrinsics::likely(b)
+        && core::intrinsics::unlikely(b)
+}
+pub unsafe fn g<T>(dst: *mut T, val: T) {
+    core::intrinsics::nontemporal_store(dst, val)
+}
+";
+
+// Compiles small test crates with the compiler and flags the crate itself
+// is built with, to find out which APIs are available.
+struct Probe {
+    rustc: std::ffi::OsString,
+    out_dir: std::path::PathBuf,
+    target: String,
+    flags: Vec<String>,
+}
+
+impl Probe {
+    // Returns `None` if even an empty crate does not compile, for example
+    // when `core` is built from source with `-Zbuild-std`.
+    fn new() -> Option<Probe> {
+        let probe = Probe {
+            rustc: env::var_os("RUSTC")?,
+            out_dir: std::path::PathBuf::from(env::var_os("OUT_DIR")?).join("probes"),
+            target: env::var("TARGET").ok()?,
+            flags: env::var("CARGO_ENCODED_RUSTFLAGS")
+                .unwrap_or_default()
+                .split('\x1f')
+                .filter(|f| !f.is_empty())
+                .map(String::from)
+                .collect(),
+        };
+        std::fs::create_dir_all(&probe.out_dir).ok()?;
+        if probe.compiles("empty", "") {
+            Some(probe)
+        } else {
+            None
+        }
+    }
+
+    fn compiles(&self, name: &str, code: &str) -> bool {
+        let source = self.out_dir.join(format!("{}.rs", name));
+        let code = format!("#![no_std]\n{}", code);
+        if std::fs::write(&source, code).is_err() {
+            return false;
+        }
+        std::process::Command::new(&self.rustc)
+            .arg("--crate-name")
+            .arg(format!("branches_probe_{}", name))
+            .args(["--crate-type=lib", "--emit=metadata", "--edition=2018"])
+            .args(["--cap-lints=allow", "--target", &self.target])
+            .arg("--out-dir")
+            .arg(&self.out_dir)
+            .args(&self.flags)
+            .arg(&source)
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .status()
+            .map_or(false, |status| status.success())
+    }
+}
+
+// Turns the recorded profile named by `BRANCHES_PROFILE`, if any, into the
+// table `hint!` looks its call site up in.
+fn write_profile() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-env-changed=BRANCHES_PROFILE");
+
+    let mut table = String::from("pub(crate) const PROFILE: &[(&str, u32, u32, u8)] = &[\n");
+    if let Some(path) = env::var_os("BRANCHES_PROFILE").filter(|p| !p.is_empty()) {
+        let path = std::path::PathBuf::from(path);
+        println!("cargo:rerun-if-changed={}", path.display());
+        let text = std::fs::read_to_string(&path).unwrap_or_else(|err| {
+            panic!("cannot read BRANCHES_PROFILE {}: {}", path.display(), err)
+        });
+        for site in parse_profile(&text) {
+            let total = site.taken + site.not_taken;
+            if site.hint == "cold" || total == 0 {
+                continue;
+            }
+            // Same threshold as `expect_with_probability`.
+            let ratio = site.taken as f64 / total as f64;
+            let direction = if ratio >= 0.9 {
+                1
+            } else if 1.0 - ratio >= 0.9 {
+                2
             } else {
-                println!("cargo:rustc-cfg=branches_stable");
+                continue;
+            };
+            table.push_str(&format!(
+                "    ({:?}, {}, {}, {}),\n",
+                site.file, site.line, site.column, direction
+            ));
+        }
+    }
+    table.push_str("];\n");
+
+    let out = std::path::PathBuf::from(env::var_os("OUT_DIR").unwrap()).join("profile.rs");
+    std::fs::write(out, table).unwrap();
+}
+
+// One call site of a profile written by `branches::stats::write`.
+#[derive(Default)]
+struct ProfileSite {
+    file: String,
+    line: u32,
+    column: u32,
+    hint: String,
+    taken: u64,
+    not_taken: u64,
+}
+
+impl ProfileSite {
+    fn set(&mut self, key: &str, value: &str) {
+        match key {
+            "file" => self.file = value.to_string(),
+            "line" => self.line = value.parse().unwrap_or(0),
+            "column" => self.column = value.parse().unwrap_or(0),
+            "hint" => self.hint = value.to_string(),
+            "taken" => self.taken = value.parse().unwrap_or(0),
+            "not_taken" => self.not_taken = value.parse().unwrap_or(0),
+            _ => {}
+        }
+    }
+}
+
+// Parses a profile in the JSON lines or CSV format of `branches::stats`.
+fn parse_profile(text: &str) -> Vec<ProfileSite> {
+    let mut sites = Vec::new();
+    if text.trim_start().starts_with('{') {
+        for line in text.lines().filter(|l| !l.trim().is_empty()) {
+            let mut site = ProfileSite::default();
+            for (key, value) in json_fields(line) {
+                site.set(&key, &value);
+            }
+            sites.push(site);
+        }
+    } else {
+        let mut rows = csv_rows(text).into_iter();
+        let header = rows.next().unwrap_or_default();
+        for row in rows {
+            let mut site = ProfileSite::default();
+            for (key, value) in header.iter().zip(row.iter()) {
+                site.set(key, value);
+            }
+            sites.push(site);
+        }
+    }
+    sites
+}
+
+// Returns the fields of a flat JSON object, with strings unescaped and other
+// values as written.
+fn json_fields(line: &str) -> Vec<(String, String)> {
+    let mut fields = Vec::new();
+    let mut chars = line.chars().peekable();
+    let mut key = None;
+    while let Some(c) = chars.next() {
+        let value = match c {
+            '"' => {
+                let mut s = String::new();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' => match chars.next() {
+                            Some('n') => s.push('\n'),
+                            Some('r') => s.push('\r'),
+                            Some('t') => s.push('\t'),
+                            Some('u') => {
+                                let hex: String = chars.by_ref().take(4).collect();
+                                let code = u32::from_str_radix(&hex, 16).unwrap_or(0xFFFD);
+                                s.push(char::from_u32(code).unwrap_or('\u{FFFD}'));
+                            }
+                            Some(c) => s.push(c),
+                            None => {}
+                        },
+                        c => s.push(c),
+                    }
+                }
+                s
+            }
+            c if c == '-' || c.is_ascii_alphanumeric() => {
+                let mut s = c.to_string();
+                while let Some(&c) = chars.peek() {
+                    if c == ',' || c == '}' || c.is_whitespace() {
+                        break;
+                    }
+                    s.push(c);
+                    chars.next();
+                }
+                s
+            }
+            _ => continue,
+        };
+        match key.take() {
+            None if chars.peek() == Some(&':') => key = Some(value),
+            None => {}
+            Some(k) => fields.push((k, value)),
+        }
+    }
+    fields
+}
+
+// Splits CSV text into rows of unquoted fields.
+fn csv_rows(text: &str) -> Vec<Vec<String>> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => row.push(std::mem::take(&mut field)),
+            '\r' if !quoted => {}
+            '\n' if !quoted => {
+                row.push(std::mem::take(&mut field));
+                rows.push(std::mem::take(&mut row));
+            }
+            c => field.push(c),
+        }
+    }
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push(row);
+    }
+    rows
+}
+
+// Returns the enabled target features, including the ones passed through
+// `-C target-feature`, which stable rustc hides from `cfg` when unstable.
+fn target_features() -> Vec<String> {
+    let mut features: Vec<String> = env::var("CARGO_CFG_TARGET_FEATURE")
+        .unwrap_or_default()
+        .split(',')
+        .map(String::from)
+        .collect();
+    for list in codegen_flags("target-feature") {
+        for feature in list.split(',') {
+            if let Some(name) = feature.strip_prefix('+') {
+                features.push(name.to_string());
+            } else if let Some(name) = feature.strip_prefix('-') {
+                features.retain(|f| f != name);
             }
-            if meta.semver >= Version::parse("1.81.0").unwrap() {
-                println!("cargo:rustc-cfg=rustc_ge_1_81_0");
+        }
+    }
+    features
+}
+
+// 32-bit ARM target features are unstable, so stable rustc never exposes
+// them to `cfg(target_feature)`. Work out which prefetch instructions the
+// target can assemble from the target triple and `-C` flags instead.
+fn arm_prefetch_cfgs() {
+    let target = env::var("TARGET").unwrap_or_default();
+    let arch = target.split('-').next().unwrap_or("");
+
+    let features = target_features();
+    let has = |name: &str| features.iter().any(|f| f == name);
+
+    // `pld` was introduced in ARMv5TE and is missing from Thumb-1, which
+    // rules out ARMv4T, the Thumb-only v5TE targets, ARMv6-M and ARMv8-M
+    // Baseline.
+    let pld = (has("v5te") && (!has("thumb-mode") || has("thumb2")))
+        || match arch {
+            "arm" | "armeb" | "armv5te" | "armv6" | "armv6k" | "armv8r" => true,
+            a if a.starts_with("armv7") || a.starts_with("armebv7") => true,
+            a if a.starts_with("thumbv7") || a == "thumbv8m.main" => true,
+            _ => false,
+        };
+
+    // `pldw` additionally needs the Multiprocessing Extensions, which no
+    // built-in target enables: opt in with `-C target-feature=+mp` or a
+    // `-C target-cpu` that implies them.
+    let pldw = pld
+        && (has("mp")
+            || codegen_flags("target-cpu").iter().any(|cpu| {
+                matches!(
+                    cpu.as_str(),
+                    "cortex-a5"
+                        | "cortex-a7"
+                        | "cortex-a9"
+                        | "cortex-a12"
+                        | "cortex-a15"
+                        | "cortex-a17"
+                )
+            }));
+
+    if pld {
+        println!("cargo:rustc-cfg=branches_arm_pld");
+    }
+    if pldw {
+        println!("cargo:rustc-cfg=branches_arm_pldw");
+    }
+}
+
+// Returns the value of every `-C <key>=<value>` codegen option in RUSTFLAGS.
+fn codegen_flags(key: &str) -> Vec<String> {
+    option_values("-C", "--codegen", key)
+}
+
+// Returns the value of every `-Z <key>=<value>` unstable option in RUSTFLAGS.
+fn unstable_flags(key: &str) -> Vec<String> {
+    option_values("-Z", "-Z", key)
+}
+
+// Returns the value of every `<key>=<value>` option passed with the `short`
+// or `long` flag in RUSTFLAGS.
+fn option_values(short: &str, long: &str, key: &str) -> Vec<String> {
+    let flags = env::var("CARGO_ENCODED_RUSTFLAGS").unwrap_or_default();
+    let mut args = flags.split('\x1f');
+    let mut values = Vec::new();
+    while let Some(arg) = args.next() {
+        let option = if arg == short || arg == long {
+            match args.next() {
+                Some(option) => option,
+                None => break,
             }
-            if meta.semver >= Version::parse("1.95.0").unwrap() {
-                println!("cargo:rustc-cfg=rustc_ge_1_95_0");
+        } else if let Some(option) = arg.strip_prefix(short) {
+            option
+        } else if let Some(option) = arg
+            .strip_prefix(long)
+            .and_then(|rest| rest.strip_prefix('='))
+        {
+            option
+        } else {
+            continue;
+        };
+        let mut kv = option.splitn(2, '=');
+        if kv.next() == Some(key) {
+            if let Some(value) = kv.next() {
+                values.push(value.to_string());
             }
         }
-        // If the compiler version cannot be detected, emitting no cfg at all
-        // would make the crate fail to compile with confusing type errors.
-        // Fall back to the most conservative stable code path instead.
-        Err(_) => println!("cargo:rustc-cfg=branches_stable"),
     }
+    values
 }
@@ -7,6 +7,8 @@
 
 use branches::{assume, likely, mark_unlikely, unlikely};
 
+// `mark_unlikely` re-exports `core::hint::cold_path` on rustc >= 1.95.
+#[allow(clippy::incompatible_msrv)]
 fn main() {
     let flag = std::env::args().count() > 1;
     if likely(!flag) {
@@ -17,6 +19,55 @@ fn main() {
         println!("unexpected path");
     }
     unsafe { assume(usize::MAX > 0) };
+    if branches::hint!(flag) {
+        println!("profiled path");
+    }
+    if branches::expect_with_probability(flag, false, 0.95) {
+        println!("improbable path");
+    }
+    println!(
+        "expected {}",
+        branches::expect_value(std::env::args().count(), 1)
+    );
+    branches::speculation_barrier();
+    let idx = branches::index_mask_nospec(std::env::args().count(), 4);
+    println!("masked index {}", idx);
+    println!("selected {}", branches::select_unpredictable(flag, 1u32, 2));
+    let (mut lo, mut hi) = (2u64, 1u64);
+    branches::ct_swap(lo > hi, &mut lo, &mut hi);
+    println!("ct selected {} {}", branches::ct_select(flag, lo, hi), hi);
+    branches::cond_swap(flag, &mut lo, &mut hi);
+    let mut data = [5u32, 1, 4, 2, 3];
+    let mid = branches::partition_branchless(&mut data, &3);
+    println!(
+        "partitioned {:?} at {} {:?}",
+        data,
+        mid,
+        branches::min_max(lo, hi)
+    );
+
+    let buf = [0u8; 256];
+    unsafe {
+        branches::flush_line(buf.as_ptr());
+        branches::flush_range(buf.as_ptr(), buf.len());
+        branches::clean_range(buf.as_ptr(), buf.len());
+        branches::persist_range(buf.as_ptr(), buf.len());
+        branches::sync_icache(buf.as_ptr(), buf.len());
+    }
+    branches::demote_line(buf.as_ptr());
+    branches::dma::clean_for_device(&buf);
+    let mut dma_buf = [0u8; 64];
+    let _ = branches::dma::invalidate_for_cpu(&mut dma_buf);
+
+    let mut dst = [0u64; 8];
+    let src = [1u64; 8];
+    unsafe {
+        branches::stream_store(dst.as_mut_ptr() as *mut u32, 1);
+        branches::stream_store(dst.as_mut_ptr(), 1);
+        branches::stream_store(dst.as_mut_ptr() as *mut [u64; 2], [1, 2]);
+        branches::stream_copy(dst.as_mut_ptr(), src.as_ptr(), dst.len());
+    }
+    branches::store_fence();
 
     // Reference abort() without calling it.
     let _abort: extern "C" fn() -> ! = branches::abort;
@@ -0,0 +1,451 @@
+// Characterizes the current machine for tuning code built on `branches`:
+// the crate's compile-time capabilities, the cache geometry, the load
+// latency of each cache level and what `prefetch_read_data` gains at each
+// locality.
+//
+//     branches-probe [--json] [--quick]
+
+use branches::{capabilities, prefetch_read_data, Capabilities};
+use std::fmt::Write as _;
+use std::time::Instant;
+
+const USAGE: &str = "usage: branches-probe [--json] [--quick]
+
+  --json   print a single JSON object instead of a report
+  --quick  use fewer iterations, and smaller buffers on small caches";
+
+// Cache line size assumed when the OS does not report one.
+const LINE: usize = 64;
+// Elements the gather benchmark prefetches ahead.
+const DISTANCE: usize = 16;
+
+struct Options {
+    json: bool,
+    quick: bool,
+}
+
+#[derive(Clone)]
+struct Cache {
+    level: u32,
+    kind: String,
+    size: usize,
+    line: usize,
+    ways: Option<usize>,
+}
+
+struct Latency {
+    name: String,
+    bytes: usize,
+    ns: f64,
+}
+
+struct PrefetchGain {
+    baseline_ns: f64,
+    // Nanoseconds per element for `LOCALITY` 0 to 3.
+    localities: [f64; 4],
+}
+
+fn main() {
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}\n{}", message, USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    let caps = capabilities();
+    let (caches, detected) = match cache_geometry() {
+        Some(caches) => (caches, true),
+        None => (assumed_geometry(), false),
+    };
+    let latencies = measure_latencies(&caches, &options);
+    let gain = measure_prefetch(&caches, &options);
+
+    let output = if options.json {
+        json_report(&caps, &caches, detected, &latencies, &gain)
+    } else {
+        human_report(&caps, &caches, detected, &latencies, &gain)
+    };
+    print!("{}", output);
+}
+
+fn parse_args(args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options {
+        json: false,
+        quick: false,
+    };
+    for arg in args {
+        match arg.as_str() {
+            "--json" => options.json = true,
+            "--quick" => options.quick = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            }
+            other => return Err(format!("unknown argument {:?}", other)),
+        }
+    }
+    Ok(options)
+}
+
+// Reads the data and unified caches of the first CPU from sysfs, which is
+// only available on Linux.
+fn cache_geometry() -> Option<Vec<Cache>> {
+    let dir = std::path::Path::new("/sys/devices/system/cpu/cpu0/cache");
+    let read = |index: &std::path::Path, name: &str| {
+        std::fs::read_to_string(index.join(name))
+            .ok()
+            .map(|s| s.trim().to_string())
+    };
+    let mut caches = Vec::new();
+    for entry in std::fs::read_dir(dir).ok()?.flatten() {
+        let index = entry.path();
+        let kind = match read(&index, "type") {
+            Some(kind) if kind != "Instruction" => kind,
+            _ => continue,
+        };
+        let level = read(&index, "level").and_then(|l| l.parse().ok());
+        let size = read(&index, "size").and_then(|s| parse_size(&s));
+        if let (Some(level), Some(size)) = (level, size) {
+            caches.push(Cache {
+                level,
+                kind,
+                size,
+                line: read(&index, "coherency_line_size")
+                    .and_then(|l| l.parse().ok())
+                    .unwrap_or(LINE),
+                ways: read(&index, "ways_of_associativity").and_then(|w| w.parse().ok()),
+            });
+        }
+    }
+    caches.sort_by_key(|cache| cache.level);
+    if caches.is_empty() {
+        None
+    } else {
+        Some(caches)
+    }
+}
+
+// Parses sysfs sizes such as `48K` or `2048K`.
+fn parse_size(s: &str) -> Option<usize> {
+    let (digits, unit) = match s.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((i, _)) => s.split_at(i),
+        None => (s, ""),
+    };
+    let value: usize = digits.parse().ok()?;
+    match unit {
+        "" => Some(value),
+        "K" => Some(value << 10),
+        "M" => Some(value << 20),
+        "G" => Some(value << 30),
+        _ => None,
+    }
+}
+
+// A typical desktop hierarchy, for systems that do not report one.
+fn assumed_geometry() -> Vec<Cache> {
+    [(1, 32 << 10), (2, 256 << 10), (3, 8 << 20)]
+        .iter()
+        .map(|&(level, size)| Cache {
+            level,
+            kind: "Unified".to_string(),
+            size,
+            line: LINE,
+            ways: None,
+        })
+        .collect()
+}
+
+// Size of a buffer that does not fit in any cache: four times the last
+// level, so at most a quarter of the random loads can hit in it.
+fn memory_bytes(caches: &[Cache], options: &Options) -> usize {
+    let largest = caches.iter().map(|c| c.size).max().unwrap_or(0);
+    let floor = if options.quick { 16 << 20 } else { 64 << 20 };
+    (largest * 4).max(floor)
+}
+
+fn measure_latencies(caches: &[Cache], options: &Options) -> Vec<Latency> {
+    let loads = if options.quick { 200_000 } else { 4_000_000 };
+    let mut latencies: Vec<Latency> = caches
+        .iter()
+        .map(|cache| {
+            // Half the cache, so the chain stays resident next to whatever
+            // else the process touches.
+            let bytes = cache.size / 2;
+            Latency {
+                name: format!("L{}", cache.level),
+                bytes,
+                ns: chase_latency(bytes, cache.line, loads),
+            }
+        })
+        .collect();
+    let bytes = memory_bytes(caches, options);
+    latencies.push(Latency {
+        name: "memory".to_string(),
+        bytes,
+        ns: chase_latency(bytes, LINE, loads / 4),
+    });
+    latencies
+}
+
+// Returns the average latency of dependent loads chasing a random cycle
+// through `bytes` bytes, one load per cache line, in nanoseconds.
+fn chase_latency(bytes: usize, line: usize, loads: usize) -> f64 {
+    let stride = (line / std::mem::size_of::<usize>()).max(1);
+    let lines = (bytes / (stride * std::mem::size_of::<usize>())).max(2);
+    let mut chain = vec![0usize; lines * stride];
+
+    // Linking each line to the next one in a random permutation gives a
+    // single cycle through every line, in an order the hardware prefetchers
+    // cannot follow.
+    let mut order: Vec<usize> = (0..lines).collect();
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+    for i in (1..lines).rev() {
+        let j = rng.below(i + 1);
+        order.swap(i, j);
+    }
+    for i in 0..lines {
+        chain[order[i] * stride] = order[(i + 1) % lines] * stride;
+    }
+
+    let mut cur = 0;
+    for _ in 0..lines {
+        cur = chain[cur];
+    }
+    let start = Instant::now();
+    for _ in 0..loads {
+        cur = chain[cur];
+    }
+    let elapsed = start.elapsed();
+    // Keeps the chase from being optimized out.
+    assert!(cur < chain.len());
+    elapsed.as_secs_f64() * 1e9 / loads as f64
+}
+
+fn measure_prefetch(caches: &[Cache], options: &Options) -> PrefetchGain {
+    let words = memory_bytes(caches, options) / std::mem::size_of::<u64>();
+    let data: Vec<u64> = (0..words as u64).collect();
+    let count = if options.quick { 200_000 } else { 2_000_000 };
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+    let indices: Vec<usize> = (0..count + DISTANCE).map(|_| rng.below(words)).collect();
+
+    let runs = if options.quick { 1 } else { 3 };
+    let best = |gather: fn(&[u64], &[usize]) -> u64| {
+        (0..runs)
+            .map(|_| {
+                let start = Instant::now();
+                let sum = gather(&data, &indices);
+                let elapsed = start.elapsed();
+                assert!(sum != u64::MAX);
+                elapsed.as_secs_f64() * 1e9 / count as f64
+            })
+            .fold(f64::INFINITY, f64::min)
+    };
+    PrefetchGain {
+        baseline_ns: best(gather_plain),
+        localities: [
+            best(gather_prefetch::<0>),
+            best(gather_prefetch::<1>),
+            best(gather_prefetch::<2>),
+            best(gather_prefetch::<3>),
+        ],
+    }
+}
+
+// Sums `data` at random `indices`, the last `DISTANCE` of which are only
+// prefetch targets.
+fn gather_plain(data: &[u64], indices: &[usize]) -> u64 {
+    let mut sum = 0u64;
+    for &i in &indices[..indices.len() - DISTANCE] {
+        sum = sum.wrapping_add(data[i]);
+    }
+    sum
+}
+
+fn gather_prefetch<const LOCALITY: i32>(data: &[u64], indices: &[usize]) -> u64 {
+    let mut sum = 0u64;
+    for k in 0..indices.len() - DISTANCE {
+        prefetch_read_data::<_, LOCALITY>(data.as_ptr().wrapping_add(indices[k + DISTANCE]));
+        sum = sum.wrapping_add(data[indices[k]]);
+    }
+    sum
+}
+
+// xorshift64, good enough to defeat the hardware prefetchers.
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, n: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % n as u64) as usize
+    }
+}
+
+fn human_report(
+    caps: &Capabilities,
+    caches: &[Cache],
+    detected: bool,
+    latencies: &[Latency],
+    gain: &PrefetchGain,
+) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "capabilities: {}", caps);
+
+    let _ = writeln!(
+        out,
+        "\ncaches{}:",
+        if detected {
+            ""
+        } else {
+            " (not reported, assumed)"
+        }
+    );
+    for cache in caches {
+        let _ = write!(
+            out,
+            "  L{} {:<8} {:>8} KiB, {} B lines",
+            cache.level,
+            cache.kind,
+            cache.size >> 10,
+            cache.line
+        );
+        match cache.ways {
+            Some(ways) => {
+                let _ = writeln!(out, ", {}-way", ways);
+            }
+            None => out.push('\n'),
+        }
+    }
+
+    let _ = writeln!(out, "\nload latency:");
+    for latency in latencies {
+        let _ = writeln!(
+            out,
+            "  {:<7} {:>8} KiB  {:>7.2} ns",
+            latency.name,
+            latency.bytes >> 10,
+            latency.ns
+        );
+    }
+
+    let _ = writeln!(
+        out,
+        "\nprefetch_read_data on random gathers, {} elements ahead:",
+        DISTANCE
+    );
+    let _ = writeln!(out, "  none          {:>7.2} ns/element", gain.baseline_ns);
+    for (locality, &ns) in gain.localities.iter().enumerate() {
+        let _ = writeln!(
+            out,
+            "  LOCALITY={}    {:>7.2} ns/element  {}",
+            locality,
+            ns,
+            speedup(gain.baseline_ns, ns).map_or("n/a".to_string(), |s| format!("{:.2}x", s))
+        );
+    }
+    out
+}
+
+// Returns how many times faster `ns` is than `baseline_ns`, or `None` when
+// that is not a finite number, such as with a timing too short to measure.
+fn speedup(baseline_ns: f64, ns: f64) -> Option<f64> {
+    let ratio = baseline_ns / ns;
+    if ns > 0.0 && ratio.is_finite() {
+        Some(ratio)
+    } else {
+        None
+    }
+}
+
+// Formats a measurement for JSON, which has no NaN or infinity.
+fn json_number(value: Option<f64>) -> String {
+    match value {
+        Some(value) if value.is_finite() => format!("{:.3}", value),
+        _ => "null".to_string(),
+    }
+}
+
+fn json_report(
+    caps: &Capabilities,
+    caches: &[Cache],
+    detected: bool,
+    latencies: &[Latency],
+    gain: &PrefetchGain,
+) -> String {
+    let mut out = String::new();
+    let cfgs: Vec<String> = caps.cfgs.iter().map(|s| format!("\"{}\"", s)).collect();
+    let localities = |enabled: bool, honored: &[bool; 4]| {
+        let levels: Vec<String> = (0..4)
+            .filter(|&i| enabled && honored[i])
+            .map(|i| i.to_string())
+            .collect();
+        format!("[{}]", levels.join(","))
+    };
+
+    let _ = write!(
+        out,
+        "{{\"capabilities\":{{\"backend\":\"{}\",\"codegen_backend\":\"{}\",\"cfgs\":[{}],\
+         \"branch_hints\":{},\"assume\":\"{}\",\"prefetch_read\":{},\"prefetch_write\":{},\
+         \"prefetch_instruction\":{},\"read_localities\":{},\"write_localities\":{},\
+         \"abort\":\"{}\"}}",
+        caps.backend.as_str(),
+        caps.codegen_backend.as_str(),
+        cfgs.join(","),
+        caps.branch_hints,
+        caps.assume.as_str(),
+        caps.prefetch_read,
+        caps.prefetch_write,
+        caps.prefetch_instruction,
+        localities(caps.prefetch_read, &caps.read_localities),
+        localities(caps.prefetch_write, &caps.write_localities),
+        caps.abort.as_str(),
+    );
+
+    let _ = write!(out, ",\"caches_detected\":{},\"caches\":[", detected);
+    for (i, cache) in caches.iter().enumerate() {
+        let _ = write!(
+            out,
+            "{}{{\"level\":{},\"type\":\"{}\",\"size\":{},\"line\":{},\"ways\":{}}}",
+            if i == 0 { "" } else { "," },
+            cache.level,
+            cache.kind,
+            cache.size,
+            cache.line,
+            cache.ways.map_or("null".to_string(), |w| w.to_string())
+        );
+    }
+
+    out.push_str("],\"latency\":[");
+    for (i, latency) in latencies.iter().enumerate() {
+        let _ = write!(
+            out,
+            "{}{{\"level\":\"{}\",\"bytes\":{},\"ns\":{}}}",
+            if i == 0 { "" } else { "," },
+            latency.name,
+            latency.bytes,
+            json_number(Some(latency.ns))
+        );
+    }
+
+    let _ = write!(
+        out,
+        "],\"prefetch\":{{\"distance\":{},\"baseline_ns\":{},\"localities\":[",
+        DISTANCE,
+        json_number(Some(gain.baseline_ns))
+    );
+    for (locality, &ns) in gain.localities.iter().enumerate() {
+        let _ = write!(
+            out,
+            "{}{{\"locality\":{},\"ns\":{},\"speedup\":{}}}",
+            if locality == 0 { "" } else { "," },
+            locality,
+            json_number(Some(ns)),
+            json_number(speedup(gain.baseline_ns, ns))
+        );
+    }
+    out.push_str("]}}\n");
+    out
+}
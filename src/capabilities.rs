@@ -0,0 +1,346 @@
+// What this build of the crate actually does, as decided by the build script.
+
+use core::fmt;
+
+/// Implementation used for the hints, chosen by the build script.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Backend {
+    /// Stable Rust: cold functions, `core::hint` and inline assembly.
+    Stable,
+    /// `core::intrinsics`, on nightly toolchains.
+    Nightly,
+}
+
+impl Backend {
+    /// Returns `"stable"` or `"nightly"`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Backend::Stable => "stable",
+            Backend::Nightly => "nightly",
+        }
+    }
+}
+
+/// Codegen backend the crate was compiled with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CodegenBackend {
+    /// The default LLVM backend.
+    Llvm,
+    /// `rustc_codegen_cranelift`, which gets portable fallbacks instead of
+    /// inline assembly and prefetches.
+    Cranelift,
+    /// `rustc_codegen_gcc`.
+    Gcc,
+}
+
+impl CodegenBackend {
+    /// Returns `"llvm"`, `"cranelift"` or `"gcc"`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CodegenBackend::Llvm => "llvm",
+            CodegenBackend::Cranelift => "cranelift",
+            CodegenBackend::Gcc => "gcc",
+        }
+    }
+}
+
+/// What [`assume`](crate::assume) does with its condition.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AssumeBehavior {
+    /// The condition is passed to the optimizer, and a false one is
+    /// undefined behavior.
+    Hint,
+    /// A false condition panics, as under Miri, sanitizers or
+    /// `BRANCHES_NO_ASSUME=check`.
+    Check,
+    /// The condition is ignored, as with `BRANCHES_NO_ASSUME=1`.
+    Ignore,
+}
+
+impl AssumeBehavior {
+    /// Returns `"hint"`, `"check"` or `"ignore"`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AssumeBehavior::Hint => "hint",
+            AssumeBehavior::Check => "check",
+            AssumeBehavior::Ignore => "ignore",
+        }
+    }
+}
+
+/// How [`abort`](crate::abort) terminates the process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AbortBehavior {
+    /// `std::process::abort()`, which raises `SIGABRT` on Unix.
+    Process,
+    /// A trap instruction, without `std` on nightly.
+    Trap,
+    /// A panic, without `std` on stable. Whether the process terminates
+    /// depends on the panic handler.
+    Panic,
+}
+
+impl AbortBehavior {
+    /// Returns `"process"`, `"trap"` or `"panic"`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AbortBehavior::Process => "process",
+            AbortBehavior::Trap => "trap",
+            AbortBehavior::Panic => "panic",
+        }
+    }
+}
+
+/// What this build of the crate emits on the running target, as returned by
+/// [`capabilities`].
+///
+/// Locality arrays are indexed by the `LOCALITY` parameter of the prefetch
+/// functions (`0` = L1, `1` = L2, `2` = L3, `3` = non-temporal). An entry
+/// is `true` when that value produces a hint for its own cache level, and
+/// `false` when it is folded into another level or not emitted at all.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Capabilities {
+    /// Implementation used for the hints.
+    pub backend: Backend,
+    /// Codegen backend the crate was compiled with.
+    pub codegen_backend: CodegenBackend,
+    /// Configuration flags set by the build script: rustc versions, probed
+    /// `core` APIs, target-specific instructions and kill switches.
+    pub cfgs: &'static [&'static str],
+    /// Whether [`likely`](crate::likely), [`unlikely`](crate::unlikely) and
+    /// [`mark_unlikely`](crate::mark_unlikely) give the optimizer a hint,
+    /// which `BRANCHES_NO_HINTS` turns off.
+    pub branch_hints: bool,
+    /// What [`assume`](crate::assume) does.
+    pub assume: AssumeBehavior,
+    /// Whether `prefetch_read_data` emits an instruction.
+    pub prefetch_read: bool,
+    /// Whether `prefetch_write_data` emits an instruction. On x86 with the
+    /// `detect` feature, this may be a read prefetch on CPUs without a
+    /// write prefetch instruction.
+    pub prefetch_write: bool,
+    /// Whether instructions can be prefetched. The crate has no instruction
+    /// prefetch function, so this is always `false`.
+    pub prefetch_instruction: bool,
+    /// Localities `prefetch_read_data` honors.
+    pub read_localities: [bool; 4],
+    /// Localities `prefetch_write_data` honors, detected on the running CPU
+    /// with the `detect` feature.
+    pub write_localities: [bool; 4],
+    /// How [`abort`](crate::abort) terminates the process.
+    pub abort: AbortBehavior,
+}
+
+/// Reports what this build of the crate emits on the running target.
+///
+/// Everything but the x86 write prefetch with the `detect` feature is known
+/// at compile time, so this is cheap enough to call at startup and log:
+///
+/// ```rust
+/// let caps = branches::capabilities();
+/// println!("branches: {}", caps);
+/// if !caps.prefetch_read {
+///     println!("prefetch_read_data is a no-op in this build");
+/// }
+/// ```
+///
+/// The [`Display`](fmt::Display) output is a single line of `key=value`
+/// pairs.
+pub fn capabilities() -> Capabilities {
+    let read = read_localities();
+    let write = write_localities();
+    Capabilities {
+        backend: if cfg!(branches_nightly) {
+            Backend::Nightly
+        } else {
+            Backend::Stable
+        },
+        codegen_backend: if cfg!(branches_codegen = "cranelift") {
+            CodegenBackend::Cranelift
+        } else if cfg!(branches_codegen = "gcc") {
+            CodegenBackend::Gcc
+        } else {
+            CodegenBackend::Llvm
+        },
+        cfgs: CFGS,
+        branch_hints: !cfg!(branches_no_hints),
+        assume: if cfg!(branches_assume_check) {
+            AssumeBehavior::Check
+        } else if cfg!(branches_no_assume) {
+            AssumeBehavior::Ignore
+        } else {
+            AssumeBehavior::Hint
+        },
+        prefetch_read: read.is_some(),
+        prefetch_write: write.is_some(),
+        prefetch_instruction: false,
+        read_localities: read.unwrap_or([false; 4]),
+        write_localities: write.unwrap_or([false; 4]),
+        abort: if cfg!(feature = "std") {
+            AbortBehavior::Process
+        } else if cfg!(branches_nightly) {
+            AbortBehavior::Trap
+        } else {
+            AbortBehavior::Panic
+        },
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "backend={} codegen={} branch_hints={} assume={} prefetch_read={} \
+             prefetch_write={} prefetch_instruction={} abort={} cfgs=",
+            self.backend.as_str(),
+            self.codegen_backend.as_str(),
+            self.branch_hints,
+            self.assume.as_str(),
+            Localities(self.prefetch_read, self.read_localities),
+            Localities(self.prefetch_write, self.write_localities),
+            self.prefetch_instruction,
+            self.abort.as_str(),
+        )?;
+        let mut separator = "";
+        for cfg in self.cfgs {
+            write!(f, "{}{}", separator, cfg)?;
+            separator = ",";
+        }
+        Ok(())
+    }
+}
+
+// Writes `false`, or the honored localities such as `L1,L3`.
+struct Localities(bool, [bool; 4]);
+
+impl fmt::Display for Localities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.0 {
+            return f.write_str("false");
+        }
+        let names = ["L1", "L2", "L3", "NTA"];
+        let mut separator = "";
+        for (name, &honored) in names.iter().zip(self.1.iter()) {
+            if honored {
+                write!(f, "{}{}", separator, name)?;
+                separator = ",";
+            }
+        }
+        Ok(())
+    }
+}
+
+const CFGS: &[&str] = &[
+    #[cfg(rustc_ge_1_60_0)]
+    "rustc_ge_1_60_0",
+    #[cfg(rustc_ge_1_72_0)]
+    "rustc_ge_1_72_0",
+    #[cfg(rustc_ge_1_84_0)]
+    "rustc_ge_1_84_0",
+    #[cfg(branches_assert_unchecked)]
+    "branches_assert_unchecked",
+    #[cfg(branches_select_unpredictable)]
+    "branches_select_unpredictable",
+    #[cfg(branches_cold_path)]
+    "branches_cold_path",
+    #[cfg(branches_arm_pld)]
+    "branches_arm_pld",
+    #[cfg(branches_arm_pldw)]
+    "branches_arm_pldw",
+    #[cfg(branches_x86_detect)]
+    "branches_x86_detect",
+    #[cfg(branches_no_hints)]
+    "branches_no_hints",
+    #[cfg(branches_no_assume)]
+    "branches_no_assume",
+    #[cfg(branches_assume_check)]
+    "branches_assume_check",
+    #[cfg(branches_no_prefetch)]
+    "branches_no_prefetch",
+    #[cfg(branches_no_asm)]
+    "branches_no_asm",
+];
+
+const ALL: [bool; 4] = [true; 4];
+const SINGLE: [bool; 4] = [true, false, false, false];
+
+// Localities `prefetch_read_data` honors, or `None` if it emits nothing.
+// Instructions without a cache-level operand count as L1 prefetches.
+fn read_localities() -> Option<[bool; 4]> {
+    if !cfg!(feature = "prefetch") || cfg!(branches_no_prefetch) {
+        None
+    } else if cfg!(any(
+        all(
+            any(target_arch = "x86", target_arch = "x86_64"),
+            target_feature = "sse"
+        ),
+        target_arch = "aarch64",
+        all(
+            target_arch = "riscv64",
+            target_feature = "zicbop",
+            target_feature = "zihintntl"
+        )
+    )) {
+        Some(ALL)
+    } else if cfg!(all(
+        target_arch = "loongarch64",
+        rustc_ge_1_72_0,
+        branches_stable
+    )) {
+        // `preld` only knows L1 and L3.
+        Some([true, false, true, false])
+    } else if cfg!(any(
+        all(target_arch = "arm", branches_arm_pld),
+        all(target_arch = "riscv64", target_feature = "zicbop"),
+        all(target_arch = "s390x", rustc_ge_1_84_0),
+        all(
+            branches_nightly,
+            any(
+                target_arch = "loongarch64",
+                target_arch = "s390x",
+                target_arch = "mips",
+                target_arch = "mips64",
+                target_arch = "powerpc",
+                target_arch = "powerpc64"
+            )
+        )
+    )) {
+        Some(SINGLE)
+    } else {
+        None
+    }
+}
+
+// Localities `prefetch_write_data` honors, or `None` if it emits nothing.
+fn write_localities() -> Option<[bool; 4]> {
+    #[cfg(all(branches_x86_detect, not(branches_no_prefetch)))]
+    {
+        let features = crate::x86_features();
+        if features & crate::X86_PREFETCHWT1 != 0 {
+            // `prefetchw` for L1, `prefetchwt1` into L2 for the rest.
+            Some([true, true, false, false])
+        } else if features & crate::X86_PRFCHW != 0 {
+            Some(SINGLE)
+        } else {
+            read_localities()
+        }
+    }
+    #[cfg(not(all(branches_x86_detect, not(branches_no_prefetch))))]
+    if cfg!(any(
+        all(
+            branches_stable,
+            any(
+                target_arch = "x86_64",
+                all(target_arch = "x86", target_feature = "sse")
+            )
+        ),
+        all(target_arch = "arm", branches_arm_pld)
+    )) && read_localities().is_some()
+    {
+        // A single write prefetch, or a read prefetch into L1.
+        Some(SINGLE)
+    } else {
+        read_localities()
+    }
+}
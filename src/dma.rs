@@ -0,0 +1,188 @@
+//! Cache maintenance for buffers shared with non-coherent DMA devices.
+//!
+//! On systems where devices do not snoop the CPU caches, a driver has to
+//! write dirty lines back to memory before a device reads a buffer, and drop
+//! stale lines after a device wrote one:
+//!
+//! ```rust
+//! use branches::dma;
+//!
+//! fn transmit(buf: &[u8]) {
+//!     dma::clean_for_device(buf);
+//!     // ... start the device transfer ...
+//! }
+//!
+//! fn receive(buf: &mut [u8]) -> Result<(), dma::MisalignedBuffer> {
+//!     // ... wait for the device transfer ...
+//!     dma::invalidate_for_cpu(buf)
+//! }
+//! ```
+//!
+//! On `aarch64`, this uses `dc cvac`/`dc ivac` followed by `dsb sy`. On
+//! `riscv32`/`riscv64` with the `zicbom` target feature, it uses
+//! `cbo.clean`/`cbo.inval` followed by `fence iorw, iorw`, assuming 64-byte
+//! cache blocks. Other targets either keep DMA coherent in hardware, like
+//! x86, or offer no cache maintenance instructions, so the functions are
+//! no-ops there.
+//!
+//! `dc ivac` is not available at EL0: on `aarch64`, [`invalidate_for_cpu`]
+//! must run at EL1 or higher, as bare-metal and kernel code does.
+
+use core::fmt;
+
+/// Error returned by [`invalidate_for_cpu`] for a buffer that shares cache
+/// lines with other data.
+///
+/// Invalidating such a line would also discard pending CPU writes to the
+/// data around the buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MisalignedBuffer {
+    /// The alignment the buffer's start and length must have, as returned
+    /// by [`alignment`].
+    pub alignment: usize,
+}
+
+impl fmt::Display for MisalignedBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "DMA buffer start and length must be multiples of {} bytes",
+            self.alignment
+        )
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for MisalignedBuffer {}
+
+/// Returns the alignment, in bytes, that DMA buffers must have for
+/// [`invalidate_for_cpu`]: the largest cache line the CPU may write back.
+///
+/// On `aarch64`, this is the writeback granule reported by `CTR_EL0.CWG`,
+/// or the architectural maximum of 2KiB when it is not reported. It is `64`
+/// on `riscv32`/`riscv64` with the `zicbom` target feature and `1`
+/// elsewhere.
+#[inline]
+pub fn alignment() -> usize {
+    #[cfg(all(target_arch = "aarch64", not(branches_no_asm)))]
+    {
+        match (crate::aarch64_ctr_el0() >> 24) & 0xF {
+            0 => 2048,
+            cwg => 4 << cwg,
+        }
+    }
+    #[cfg(all(
+        any(target_arch = "riscv32", target_arch = "riscv64"),
+        target_feature = "zicbom",
+        not(branches_no_asm)
+    ))]
+    {
+        64
+    }
+    #[cfg(any(
+        branches_no_asm,
+        not(any(
+            target_arch = "aarch64",
+            all(
+                any(target_arch = "riscv32", target_arch = "riscv64"),
+                target_feature = "zicbom"
+            )
+        ))
+    ))]
+    {
+        1
+    }
+}
+
+/// Writes `buf` back to memory so that a device can read it, then waits for
+/// the write-back to complete.
+///
+/// Call it after filling the buffer and before starting the transfer. The
+/// lines stay cached, and lines shared with data around `buf` are harmlessly
+/// written back as well, so no alignment is required.
+#[inline]
+pub fn clean_for_device(buf: &[u8]) {
+    let _ = buf;
+    #[cfg(all(target_arch = "aarch64", not(branches_no_asm)))]
+    unsafe {
+        let line = crate::aarch64_dcache_line();
+        crate::for_each_line(buf.as_ptr(), buf.len(), line, |line| {
+            core::arch::asm!(
+                "dc cvac, {}",
+                in(reg) line,
+                options(nostack, preserves_flags)
+            )
+        });
+        core::arch::asm!("dsb sy", options(nostack, preserves_flags));
+    }
+
+    #[cfg(all(
+        any(target_arch = "riscv32", target_arch = "riscv64"),
+        target_feature = "zicbom",
+        not(branches_no_asm)
+    ))]
+    unsafe {
+        crate::for_each_line(buf.as_ptr(), buf.len(), 64, |line| {
+            core::arch::asm!(
+                "cbo.clean ({})",
+                in(reg) line,
+                options(nostack, preserves_flags)
+            )
+        });
+        core::arch::asm!("fence iorw, iorw", options(nostack, preserves_flags));
+    }
+}
+
+/// Discards the cached copy of `buf` so that the CPU reads what a device
+/// wrote to memory, then waits for the invalidation to complete.
+///
+/// Call it after the transfer into the buffer completed and before reading
+/// it. Any CPU write to `buf` that was not written back before the transfer
+/// is lost.
+///
+/// # Errors
+///
+/// Returns [`MisalignedBuffer`], without touching the cache, when the start
+/// or length of `buf` is not a multiple of [`alignment`]. An empty `buf` is
+/// always accepted and does nothing, wherever it points.
+#[inline]
+pub fn invalidate_for_cpu(buf: &mut [u8]) -> Result<(), MisalignedBuffer> {
+    if buf.is_empty() {
+        return Ok(());
+    }
+    let alignment = alignment();
+    if (buf.as_ptr() as usize | buf.len()) & (alignment - 1) != 0 {
+        return Err(MisalignedBuffer { alignment });
+    }
+
+    #[cfg(all(target_arch = "aarch64", not(branches_no_asm)))]
+    unsafe {
+        let line = crate::aarch64_dcache_line();
+        crate::for_each_line(buf.as_mut_ptr(), buf.len(), line, |line| {
+            core::arch::asm!(
+                "dc ivac, {}",
+                in(reg) line,
+                options(nostack, preserves_flags)
+            )
+        });
+        core::arch::asm!("dsb sy", options(nostack, preserves_flags));
+    }
+
+    #[cfg(all(
+        any(target_arch = "riscv32", target_arch = "riscv64"),
+        target_feature = "zicbom",
+        not(branches_no_asm)
+    ))]
+    unsafe {
+        crate::for_each_line(buf.as_mut_ptr(), buf.len(), 64, |line| {
+            core::arch::asm!(
+                "cbo.inval ({})",
+                in(reg) line,
+                options(nostack, preserves_flags)
+            )
+        });
+        core::arch::asm!("fence iorw, iorw", options(nostack, preserves_flags));
+    }
+
+    Ok(())
+}
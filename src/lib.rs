@@ -12,14 +12,31 @@
 // call to a `#[cold]` function inside the branch. Any form of inlining
 // (`#[inline]` or `#[inline(always)]`) removes the call during optimization
 // and with it the hint, turning `likely`/`unlikely` into no-ops.
-#[cfg(all(branches_stable, not(rustc_ge_1_95_0)))]
+#[cfg(all(branches_stable, not(branches_no_hints), not(branches_cold_path)))]
 #[inline(never)]
 #[cold]
 const fn cold_and_empty() {}
 
-#[cfg(all(branches_stable, rustc_ge_1_95_0))]
+#[cfg(all(branches_stable, not(branches_no_hints), branches_cold_path))]
 use core::hint::cold_path as cold_and_empty;
 
+mod capabilities;
+pub mod dma;
+mod select;
+mod sort;
+#[cfg(feature = "record")]
+pub mod stats;
+#[cfg(all(feature = "std", feature = "prefetch"))]
+mod tune;
+
+pub use capabilities::{
+    capabilities, AbortBehavior, AssumeBehavior, Backend, Capabilities, CodegenBackend,
+};
+pub use select::{ct_select, ct_swap, select_unpredictable, CtSelect, Select};
+pub use sort::{cond_swap, min_max, partition_branchless};
+#[cfg(all(feature = "std", feature = "prefetch"))]
+pub use tune::{tune_distance, Tuning, TuningFile};
+
 /// Aborts the execution of the process immediately and without any cleanup.
 ///
 /// This function is used to indicate a critical and unrecoverable error in the program.
@@ -61,24 +78,35 @@ pub extern "C" fn abort() -> ! {
 ///
 /// This intrinsic is marked unsafe because it can result in undefined behavior
 /// if the condition passed to it is false.
+///
+/// Building with `BRANCHES_NO_ASSUME=1` turns it into a no-op, and with
+/// `BRANCHES_NO_ASSUME=check` into an `assert!` that panics on a false
+/// condition.
 #[inline(always)]
+#[cfg_attr(branches_assume_check, track_caller)]
+// `assert_unchecked` is only called when build.rs found it.
+#[allow(clippy::incompatible_msrv)]
 pub unsafe fn assume(b: bool) {
-    #[cfg(branches_stable)]
+    #[cfg(branches_assume_check)]
+    assert!(b, "branches::assume() called with a false condition");
+    #[cfg(any(branches_no_assume, branches_assume_check))]
+    let _ = b;
+    #[cfg(all(branches_stable, not(branches_no_assume), not(branches_assume_check)))]
     {
         // Rust >= 1.81.0: use the newer `assert_unchecked` hint.
-        #[cfg(rustc_ge_1_81_0)]
+        #[cfg(branches_assert_unchecked)]
         {
             core::hint::assert_unchecked(b)
         }
         // Rust < 1.81.0: fall back to the older `unreachable_unchecked`.
-        #[cfg(not(rustc_ge_1_81_0))]
+        #[cfg(not(branches_assert_unchecked))]
         {
             if !b {
                 core::hint::unreachable_unchecked()
             }
         }
     }
-    #[cfg(branches_nightly)]
+    #[cfg(all(branches_nightly, not(branches_no_assume), not(branches_assume_check)))]
     core::intrinsics::assume(b)
 }
 
@@ -90,18 +118,34 @@ pub unsafe fn assume(b: bool) {
 ///
 /// Unlike most intrinsics, this function is safe to call and doesn't require an `unsafe` block.
 /// Therefore, implementations must not require the user to uphold any safety invariants.
+///
+/// Building with `BRANCHES_NO_HINTS=1` turns it into the identity function.
+///
+/// With the `record` feature, the outcome is counted for this call site, see
+/// the `stats` module.
 #[must_use = "the hint only takes effect when the returned value is used as a branch condition"]
 #[inline(always)]
+#[cfg_attr(feature = "record", track_caller)]
+// `cold_and_empty` is `core::hint::cold_path` when build.rs found it.
+#[allow(clippy::incompatible_msrv)]
 pub fn likely(b: bool) -> bool {
-    #[cfg(branches_stable)]
+    #[cfg(feature = "record")]
+    stats::record(core::panic::Location::caller(), stats::Hint::Likely, b);
+    #[cfg(all(branches_stable, not(branches_no_hints)))]
     {
         if !b {
             cold_and_empty();
         }
         b
     }
-    #[cfg(branches_nightly)]
-    core::intrinsics::likely(b)
+    #[cfg(all(branches_nightly, not(branches_no_hints)))]
+    {
+        core::intrinsics::likely(b)
+    }
+    #[cfg(branches_no_hints)]
+    {
+        b
+    }
 }
 
 /// Marks a code block as cold, indicating to the compiler that it is unlikely to be called.
@@ -138,9 +182,13 @@ pub fn likely(b: bool) -> bool {
 ///     }
 /// }
 /// ```
-#[cfg(not(rustc_ge_1_95_0))]
-#[cold]
-#[inline(never)]
+#[cfg(all(
+    not(feature = "record"),
+    any(not(branches_cold_path), branches_no_hints)
+))]
+#[cfg_attr(not(branches_no_hints), cold)]
+#[cfg_attr(not(branches_no_hints), inline(never))]
+#[cfg_attr(branches_no_hints, inline(always))]
 pub const fn mark_unlikely() {}
 /// Marks a code block as cold, indicating to the compiler that it is unlikely to be called.
 /// This can help the compiler optimize for the common case.
@@ -176,8 +224,52 @@ pub const fn mark_unlikely() {}
 ///     }
 /// }
 /// ```
-#[cfg(rustc_ge_1_95_0)]
+#[cfg(all(branches_cold_path, not(feature = "record"), not(branches_no_hints)))]
 pub use core::hint::cold_path as mark_unlikely;
+/// Marks a code block as cold, indicating to the compiler that it is unlikely to be called.
+/// This can help the compiler optimize for the common case.
+///
+/// This function does not take any arguments and does not return any value.
+/// It is primarily used to mark functions or code paths that are rarely executed,
+/// such as error handling or panic paths.
+///
+/// Example: marking the error variant of a match as unlikely.
+///
+/// In many hot paths a value is expected to be the success variant.
+/// By marking the error arm using `mark_unlikely` we give the optimizer a hint
+/// that this branch is rarely taken.
+///
+/// ```rust
+/// use branches::{mark_unlikely};
+///
+/// #[derive(Debug)]
+/// enum Status {
+///     Ok(i32),
+///     Err(String),
+/// }
+///
+/// fn get_value(status: Status) -> i32 {
+///     match status {
+///         Status::Ok(v) => v,
+///         // The error case is rare, hint the compiler accordingly.
+///         Status::Err(err) => {
+///             mark_unlikely();
+///             eprintln!("unexpected error: {:?}", err);
+///             -1
+///         }
+///     }
+/// }
+/// ```
+///
+/// With the `record` feature, this is not a `const fn`, and every time the
+/// path is reached is counted for this call site, see the `stats` module.
+#[cfg(feature = "record")]
+#[cfg_attr(not(branches_no_hints), cold)]
+#[inline(never)]
+#[track_caller]
+pub fn mark_unlikely() {
+    stats::record(core::panic::Location::caller(), stats::Hint::Cold, true);
+}
 
 /// Hints to the compiler that the branch condition is unlikely to be true.
 /// Returns the value passed to it.
@@ -187,18 +279,335 @@ pub use core::hint::cold_path as mark_unlikely;
 ///
 /// Unlike most intrinsics, this function is safe to call and doesn't require an `unsafe` block.
 /// Therefore, implementations must not require the user to uphold any safety invariants.
+///
+/// Building with `BRANCHES_NO_HINTS=1` turns it into the identity function.
+///
+/// With the `record` feature, the outcome is counted for this call site, see
+/// the `stats` module.
 #[must_use = "the hint only takes effect when the returned value is used as a branch condition"]
 #[inline(always)]
+#[cfg_attr(feature = "record", track_caller)]
+// `cold_and_empty` is `core::hint::cold_path` when build.rs found it.
+#[allow(clippy::incompatible_msrv)]
 pub fn unlikely(b: bool) -> bool {
-    #[cfg(branches_stable)]
+    #[cfg(feature = "record")]
+    stats::record(core::panic::Location::caller(), stats::Hint::Unlikely, b);
+    #[cfg(all(branches_stable, not(branches_no_hints)))]
     {
         if b {
             cold_and_empty();
         }
         b
     }
-    #[cfg(branches_nightly)]
-    core::intrinsics::unlikely(b)
+    #[cfg(all(branches_nightly, not(branches_no_hints)))]
+    {
+        core::intrinsics::unlikely(b)
+    }
+    #[cfg(branches_no_hints)]
+    {
+        b
+    }
+}
+
+// Probability from which `expect_with_probability` emits a hint. `likely` and
+// `unlikely` tell LLVM the branch goes one way about 2000 times out of 2001,
+// so weaker expectations get no hint rather than an exaggerated one.
+const PROBABILITY_HINT_THRESHOLD: f64 = 0.9;
+
+/// Hints to the compiler that `b` equals `expected` with the given
+/// `probability`. Returns the value passed to it.
+///
+/// This mirrors C's `__builtin_expect_with_probability(b, expected, p)`:
+///
+/// ```rust
+/// use branches::expect_with_probability;
+///
+/// fn is_hit(key: u32) -> bool {
+///     // Roughly 95% of lookups hit the cache.
+///     if expect_with_probability(key % 20 != 0, true, 0.95) {
+///         return true;
+///     }
+///     false
+/// }
+/// # assert!(is_hit(3));
+/// # assert!(!is_hit(40));
+/// ```
+///
+/// Rust exposes no way to pass an exact probability to LLVM
+/// (`llvm.expect.with.probability`) on stable or nightly, so the hint is
+/// rounded to the two levels the crate has:
+///
+/// - `probability >= 0.9`: [`likely`] if `expected` is true, [`unlikely`]
+///   otherwise.
+/// - `probability <= 0.1`: the opposite hint, as `b` is then likely to differ
+///   from `expected`.
+/// - anything in between, or NaN: no hint, so a 70/30 branch is left to the
+///   optimizer's own heuristics instead of being treated as a 99.95/0.05 one.
+///
+/// Use a constant `probability` so that the choice is made at compile time.
+#[must_use = "the hint only takes effect when the returned value is used as a branch condition"]
+#[inline(always)]
+#[cfg_attr(feature = "record", track_caller)]
+pub fn expect_with_probability(b: bool, expected: bool, probability: f64) -> bool {
+    if probability >= PROBABILITY_HINT_THRESHOLD {
+        if expected {
+            likely(b)
+        } else {
+            unlikely(b)
+        }
+    } else if 1.0 - probability >= PROBABILITY_HINT_THRESHOLD {
+        if expected {
+            unlikely(b)
+        } else {
+            likely(b)
+        }
+    } else {
+        b
+    }
+}
+
+/// Hints to the compiler that `v` is likely to equal `expected`.
+/// Returns the value passed to it.
+///
+/// This is the counterpart of C's `__builtin_expect(v, expected)` for any
+/// comparable value, most useful to bias `match` dispatch toward the common
+/// case:
+///
+/// ```rust
+/// use branches::expect_value;
+///
+/// fn execute(opcode: u8, acc: &mut u32) {
+///     match expect_value(opcode, 0x01) {
+///         0x01 => *acc += 1,
+///         0x02 => *acc *= 2,
+///         _ => *acc = 0,
+///     }
+/// }
+/// # let mut acc = 1;
+/// # execute(0x01, &mut acc);
+/// # execute(0x02, &mut acc);
+/// # assert_eq!(acc, 4);
+/// ```
+///
+/// The mismatch path is marked cold. `v` itself is returned on both paths,
+/// even when it only compares equal to `expected`, such as `-0.0` and `0.0`.
+#[must_use = "the hint only takes effect when the returned value is used"]
+#[inline(always)]
+#[cfg_attr(feature = "record", track_caller)]
+pub fn expect_value<T: PartialEq + Copy>(v: T, expected: T) -> T {
+    let _ = likely(v == expected);
+    v
+}
+
+// The table of profiled call sites generated by build.rs from
+// `BRANCHES_PROFILE`: file, line, column and 1 for likely or 2 for unlikely.
+mod profile {
+    include!(concat!(env!("OUT_DIR"), "/profile.rs"));
+}
+
+/// Hints the branch condition `cond` in the direction observed in a recorded
+/// profile. Returns the value of `cond`.
+///
+/// Hand-written hints go stale as workloads change. `hint!` instead looks
+/// its call site up, at compile time, in a profile recorded with the `record`
+/// feature and acts as [`likely`] if the condition was true at least 90% of
+/// the time, [`unlikely`] if it was false at least 90% of the time, and as no
+/// hint otherwise or when the site is not in the profile:
+///
+/// ```rust
+/// use branches::hint;
+///
+/// fn parse_digit(c: u8) -> Option<u8> {
+///     if hint!(c.is_ascii_digit()) {
+///         Some(c - b'0')
+///     } else {
+///         None
+///     }
+/// }
+/// # assert_eq!(parse_digit(b'7'), Some(7));
+/// ```
+///
+/// To profile, build with the `record` feature, run a representative
+/// workload with `BRANCHES_STATS=csv:<file>` (or `json:<file>`), and point
+/// the `BRANCHES_PROFILE` environment variable at that file for the
+/// optimized build. It is read by the crate's build script, so use an
+/// absolute path, for example through `.cargo/config.toml`:
+///
+/// ```toml
+/// [env]
+/// BRANCHES_PROFILE = { value = "hints.csv", relative = true }
+/// ```
+///
+/// Sites are matched by file, line and column, so re-record the profile
+/// after editing the code around them.
+#[macro_export]
+macro_rules! hint {
+    ($cond:expr) => {{
+        const DIRECTION: u8 = $crate::__profile_direction(file!(), line!(), column!());
+        $crate::__profiled_hint::<DIRECTION>($cond)
+    }};
+}
+
+// Returns the profiled direction of the call site, or 0 if it has none.
+#[doc(hidden)]
+pub const fn __profile_direction(file: &str, line: u32, column: u32) -> u8 {
+    let mut i = 0;
+    while i < profile::PROFILE.len() {
+        let (f, l, c, direction) = profile::PROFILE[i];
+        if l == line && c == column && const_str_eq(f, file) {
+            return direction;
+        }
+        i += 1;
+    }
+    0
+}
+
+const fn const_str_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut i = 0;
+    while i < a.len() {
+        if a[i] != b[i] {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+#[doc(hidden)]
+#[inline(always)]
+#[cfg_attr(feature = "record", track_caller)]
+pub fn __profiled_hint<const DIRECTION: u8>(cond: bool) -> bool {
+    match DIRECTION {
+        1 => likely(cond),
+        2 => unlikely(cond),
+        _ => {
+            #[cfg(feature = "record")]
+            stats::record(core::panic::Location::caller(), stats::Hint::Auto, cond);
+            cond
+        }
+    }
+}
+
+/// Stops the CPU from speculatively executing past this point until every
+/// earlier instruction has completed.
+///
+/// Branch hints only influence which path the CPU predicts: a bounds check
+/// under [`likely`] can still be bypassed speculatively (Spectre variant 1).
+/// Placing this barrier after the check prevents the guarded code from
+/// running before the check resolves. For array lookups,
+/// [`index_mask_nospec`] is usually cheaper.
+///
+/// Emits `lfence` on `x86`/`x86_64` (with the `sse2` target feature). On
+/// `aarch64`, emits `sb` with the `sb` target feature and the equivalent
+/// `dsb nsh; isb` sequence otherwise. On other targets this is only a
+/// compiler fence and does not stop speculation.
+#[inline(always)]
+pub fn speculation_barrier() {
+    #[cfg(all(
+        any(target_arch = "x86", target_arch = "x86_64"),
+        target_feature = "sse2",
+        not(branches_no_asm)
+    ))]
+    unsafe {
+        core::arch::asm!("lfence", options(nostack, preserves_flags))
+    }
+    #[cfg(all(target_arch = "aarch64", target_feature = "sb", not(branches_no_asm)))]
+    unsafe {
+        core::arch::asm!("sb", options(nostack, preserves_flags))
+    }
+    #[cfg(all(
+        target_arch = "aarch64",
+        not(target_feature = "sb"),
+        not(branches_no_asm)
+    ))]
+    unsafe {
+        core::arch::asm!("dsb nsh", "isb", options(nostack, preserves_flags))
+    }
+    #[cfg(any(
+        branches_no_asm,
+        not(any(
+            all(
+                any(target_arch = "x86", target_arch = "x86_64"),
+                target_feature = "sse2"
+            ),
+            target_arch = "aarch64"
+        ))
+    ))]
+    core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::SeqCst)
+}
+
+/// Returns `idx` if `idx < len` and `0` otherwise, without a branch the CPU
+/// could mispredict.
+///
+/// Use it to clamp an index right after its bounds check, so that even when
+/// the check is bypassed speculatively (Spectre variant 1), the lookup only
+/// ever reads inside the array:
+///
+/// ```rust
+/// use branches::{index_mask_nospec, likely};
+///
+/// fn lookup(table: &[u8], idx: usize) -> Option<u8> {
+///     if likely(idx < table.len()) {
+///         let idx = index_mask_nospec(idx, table.len());
+///         Some(table[idx])
+///     } else {
+///         None
+///     }
+/// }
+/// ```
+///
+/// On `x86`/`x86_64` the mask is computed with `cmp`/`sbb`, and on `aarch64`
+/// with `cmp`/`csel` followed by `csdb`, inside inline assembly so that the
+/// compiler cannot turn it back into a branch. Other targets use branchless
+/// arithmetic, which requires both values to be at most `isize::MAX`.
+#[inline(always)]
+pub fn index_mask_nospec(idx: usize, len: usize) -> usize {
+    #[cfg(all(any(target_arch = "x86", target_arch = "x86_64"), not(branches_no_asm)))]
+    {
+        let mask: usize;
+        // `sbb` turns the borrow of `idx - len` into all ones or zero.
+        unsafe {
+            core::arch::asm!(
+                "cmp {idx}, {len}",
+                "sbb {mask}, {mask}",
+                idx = in(reg) idx,
+                len = in(reg) len,
+                mask = out(reg) mask,
+                options(pure, nomem, nostack)
+            )
+        };
+        idx & mask
+    }
+    #[cfg(all(target_arch = "aarch64", not(branches_no_asm)))]
+    {
+        let clamped: usize;
+        unsafe {
+            core::arch::asm!(
+                "cmp {idx}, {len}",
+                "csel {out}, {idx}, xzr, lo",
+                "csdb",
+                idx = in(reg) idx,
+                len = in(reg) len,
+                out = lateout(reg) clamped,
+                options(pure, nomem, nostack)
+            )
+        };
+        clamped
+    }
+    #[cfg(any(
+        branches_no_asm,
+        not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64"))
+    ))]
+    {
+        // The sign bit of `idx | (len - 1 - idx)` is set exactly when
+        // `idx >= len`, as long as neither exceeds `isize::MAX`.
+        let mask = !((idx | len.wrapping_sub(1).wrapping_sub(idx)) as isize) >> (usize::BITS - 1);
+        idx & mask as usize
+    }
 }
 
 /// Prefetches data for reading into the cache.
@@ -216,21 +625,36 @@ pub fn unlikely(b: bool) -> bool {
 /// * `addr` - A pointer to the data to prefetch.
 /// * `LOCALITY` - The cache level to prefetch into: `0` = L1, `1` = L2,
 ///   `2` = L3, any other value = non-temporal. The convention is identical
-///   on stable and nightly toolchains.
+///   on stable and nightly toolchains. `pld` (32-bit `arm`) and `pfd`
+///   (`s390x`) have no cache-level operand, so `LOCALITY` is ignored there,
+///   and `loongarch64` maps everything except L1 to L3.
 ///
 /// # Supported architectures
 ///
-/// On stable, the hint is emitted on `x86`/`x86_64` (with the `sse` target
-/// feature, enabled by default on `x86_64` and `i686` targets), `aarch64`,
-/// and `riscv64` when compiled with the `zicbop` target feature
-/// (`-C target-feature=+zicbop`). On other targets this compiles to a
-/// no-op. On nightly, the hint is lowered by LLVM for every architecture
-/// that supports one.
+/// On stable, the hint is emitted on:
+///
+/// * `x86`/`x86_64` with the `sse` target feature (enabled by default on
+///   `x86_64` and `i686` targets).
+/// * `aarch64`.
+/// * 32-bit `arm` targets that support `pld` (ARMv5TE and later, except
+///   Thumb-1-only cores such as ARMv6-M).
+/// * `riscv64` when compiled with the `zicbop` target feature
+///   (`-C target-feature=+zicbop`). `LOCALITY` is only honored when the
+///   `zihintntl` target feature is enabled as well, through the `ntl.p1`,
+///   `ntl.pall` and `ntl.all` non-temporal hints.
+/// * `loongarch64` (rustc 1.72 or newer) and `s390x` (rustc 1.84 or newer).
+///
+/// On other targets this compiles to a no-op. On nightly, the hint is
+/// lowered by LLVM for every architecture that supports one, including
+/// `mips`/`mips64`, whose inline assembly is not yet stable.
+///
+/// Building with `BRANCHES_NO_PREFETCH=1` turns it into a no-op on every
+/// target.
 #[inline(always)]
 #[cfg(feature = "prefetch")]
 pub fn prefetch_read_data<T, const LOCALITY: i32>(addr: *const T) {
     let _ = addr;
-    #[cfg(branches_stable)]
+    #[cfg(all(branches_stable, not(branches_no_prefetch)))]
     {
         #[cfg(all(
             any(target_arch = "x86", target_arch = "x86_64"),
@@ -261,8 +685,17 @@ pub fn prefetch_read_data<T, const LOCALITY: i32>(addr: *const T) {
             }
         }
 
-        // `prfm` only exists on AArch64; 32-bit ARM would need `pld`, which
-        // not every 32-bit ARM target supports, so arm stays a no-op.
+        // 32-bit ARM: `pld` has no cache-level operand, so `LOCALITY` is
+        // ignored. build.rs only enables it on targets that can assemble it.
+        #[cfg(all(target_arch = "arm", branches_arm_pld))]
+        unsafe {
+            core::arch::asm!(
+                "pld [{}]",
+                in(reg) addr,
+                options(nostack, readonly, preserves_flags)
+            );
+        }
+
         #[cfg(target_arch = "aarch64")]
         unsafe {
             match LOCALITY {
@@ -291,7 +724,12 @@ pub fn prefetch_read_data<T, const LOCALITY: i32>(addr: *const T) {
 
         // The Zicbop extension is not part of the baseline riscv64gc target,
         // so the instruction is only emitted when the feature is enabled.
-        #[cfg(all(target_arch = "riscv64", target_feature = "zicbop"))]
+        // Without Zihintntl there is no way to express a cache level.
+        #[cfg(all(
+            target_arch = "riscv64",
+            target_feature = "zicbop",
+            not(target_feature = "zihintntl")
+        ))]
         unsafe {
             core::arch::asm!(
                 "prefetch.r 0({})",
@@ -300,6 +738,87 @@ pub fn prefetch_read_data<T, const LOCALITY: i32>(addr: *const T) {
             );
         }
 
+        // Zihintntl hints apply to the memory access that immediately follows
+        // them, so each one shares an `asm!` block with its prefetch.
+        #[cfg(all(
+            target_arch = "riscv64",
+            target_feature = "zicbop",
+            target_feature = "zihintntl"
+        ))]
+        unsafe {
+            match LOCALITY {
+                0 => core::arch::asm!(
+                    "prefetch.r 0({})",
+                    in(reg) addr,
+                    options(nostack, readonly, preserves_flags)
+                ), // L1 cache
+                1 => core::arch::asm!(
+                    "ntl.p1",
+                    "prefetch.r 0({})",
+                    in(reg) addr,
+                    options(nostack, readonly, preserves_flags)
+                ), // L2 cache
+                2 => core::arch::asm!(
+                    "ntl.pall",
+                    "prefetch.r 0({})",
+                    in(reg) addr,
+                    options(nostack, readonly, preserves_flags)
+                ), // L3 cache
+                _ => core::arch::asm!(
+                    "ntl.all",
+                    "prefetch.r 0({})",
+                    in(reg) addr,
+                    options(nostack, readonly, preserves_flags)
+                ), // Non-temporal
+            }
+        }
+
+        // `preld` only distinguishes L1 from L3, so every request other than
+        // L1 goes to L3, which at least keeps the line out of L1.
+        #[cfg(all(target_arch = "loongarch64", rustc_ge_1_72_0))]
+        unsafe {
+            match LOCALITY {
+                0 => core::arch::asm!(
+                    "preld 0, {}, 0",
+                    in(reg) addr,
+                    options(nostack, readonly, preserves_flags)
+                ), // L1 cache
+                _ => core::arch::asm!(
+                    "preld 2, {}, 0",
+                    in(reg) addr,
+                    options(nostack, readonly, preserves_flags)
+                ), // L3 cache
+            }
+        }
+
+        // `pfd` has no cache-level operand, so `LOCALITY` is ignored. Register
+        // 0 means "no base" in an address, hence `reg_addr`.
+        #[cfg(all(target_arch = "s390x", rustc_ge_1_84_0))]
+        unsafe {
+            core::arch::asm!(
+                "pfd 1, 0({})",
+                in(reg_addr) addr,
+                options(nostack, readonly, preserves_flags)
+            );
+        }
+
+        // this requires unstable asm feature, uncomment when stabilized
+        //#[cfg(any(target_arch = "mips", target_arch = "mips64"))]
+        //unsafe {
+        //    match LOCALITY {
+        //        0..=2 => core::arch::asm!(
+        //            "pref 0, 0({})",
+        //            in(reg) addr,
+        //            options(nostack, readonly, preserves_flags)
+        //        ), // Load
+        //        _ => core::arch::asm!(
+        //            "pref 4, 0({})",
+        //            in(reg) addr,
+        //            options(nostack, readonly, preserves_flags)
+        //        ), // Load streamed
+        //    }
+        //}
+
         // this requires unstable asm feature, uncomment when stabilized
         //#[cfg(any(target_arch = "powerpc", target_arch = "powerpc64"))]
         //unsafe {
@@ -310,7 +829,7 @@ pub fn prefetch_read_data<T, const LOCALITY: i32>(addr: *const T) {
         //    );
         //}
     }
-    #[cfg(branches_nightly)]
+    #[cfg(all(branches_nightly, not(branches_no_prefetch)))]
     {
         // `core::intrinsics` uses the opposite locality convention
         // (0 = no locality .. 3 = maximally local), so translate to keep
@@ -341,22 +860,76 @@ pub fn prefetch_read_data<T, const LOCALITY: i32>(addr: *const T) {
 /// * `addr` - A pointer to the data to prefetch.
 /// * `LOCALITY` - The cache level to prefetch into: `0` = L1, `1` = L2,
 ///   `2` = L3, any other value = non-temporal. The convention is identical
-///   on stable and nightly toolchains. On `x86_64` there is a single
-///   write-prefetch instruction, so `LOCALITY` is ignored there.
+///   on stable and nightly toolchains. `x86_64`, 32-bit `arm` and `s390x`
+///   have a single write-prefetch instruction, so `LOCALITY` is ignored
+///   there, and `loongarch64` maps everything except L1 to L3. On x86, the
+///   `detect` feature honors `LOCALITY` where the running CPU allows it.
 ///
 /// # Supported architectures
 ///
-/// On stable, the hint is emitted on `x86`/`x86_64` (with the `sse` target
-/// feature, enabled by default on `x86_64` and `i686` targets), `aarch64`,
-/// and `riscv64` when compiled with the `zicbop` target feature
-/// (`-C target-feature=+zicbop`). On other targets this compiles to a
-/// no-op. On nightly, the hint is lowered by LLVM for every architecture
-/// that supports one.
+/// On stable, the hint is emitted on:
+///
+/// * `x86`/`x86_64` with the `sse` target feature (enabled by default on
+///   `x86_64` and `i686` targets).
+/// * `aarch64`.
+/// * 32-bit `arm` targets that support `pld` (ARMv5TE and later, except
+///   Thumb-1-only cores such as ARMv6-M). `pldw` is used when the
+///   Multiprocessing Extensions are enabled (`-C target-feature=+mp`, or a
+///   `-C target-cpu` such as `cortex-a7` or `cortex-a9`); otherwise the
+///   write prefetch falls back to `pld`.
+/// * `riscv64` when compiled with the `zicbop` target feature
+///   (`-C target-feature=+zicbop`). `LOCALITY` is only honored when the
+///   `zihintntl` target feature is enabled as well, through the `ntl.p1`,
+///   `ntl.pall` and `ntl.all` non-temporal hints.
+/// * `loongarch64` (rustc 1.72 or newer) and `s390x` (rustc 1.84 or newer).
+///
+/// On other targets this compiles to a no-op. On nightly, the hint is
+/// lowered by LLVM for every architecture that supports one, including
+/// `mips`/`mips64`, whose inline assembly is not yet stable.
+///
+/// Building with `BRANCHES_NO_PREFETCH=1` turns it into a no-op on every
+/// target.
+///
+/// # Runtime detection on x86
+///
+/// By default, `x86_64` always emits `prefetchw` and 32-bit `x86` always
+/// emits `prefetcht0`. With the `detect` feature, the CPU is queried with
+/// CPUID on first use, the result is cached, and every call dispatches on
+/// it on both toolchains, following the same strategy as GCC:
+///
+/// * `prefetchwt1` for `LOCALITY` other than `0` when PREFETCHWT1 is
+///   available.
+/// * `prefetchw` when PRFCHW (or 3DNow!) is available.
+/// * Otherwise, the read prefetch [`prefetch_read_data`] emits for the same
+///   `LOCALITY`.
+///
+/// The dispatch costs a relaxed atomic load and a branch per call.
 #[inline(always)]
 #[cfg(feature = "prefetch")]
 pub fn prefetch_write_data<T, const LOCALITY: i32>(addr: *const T) {
     let _ = addr;
-    #[cfg(branches_stable)]
+    // With the `detect` feature, x86 picks the instruction at runtime on
+    // both toolchains, since LLVM only knows the compile-time features.
+    #[cfg(all(branches_x86_detect, not(branches_no_prefetch)))]
+    unsafe {
+        let features = x86_features();
+        if LOCALITY != 0 && features & X86_PREFETCHWT1 != 0 {
+            core::arch::asm!(
+                "prefetchwt1 [{}]",
+                in(reg) addr,
+                options(nostack, readonly, preserves_flags)
+            ) // Write-prefetch for L2/L3 cache or non-temporal
+        } else if features & (X86_PRFCHW | X86_PREFETCHWT1) != 0 {
+            core::arch::asm!(
+                "prefetchw [{}]",
+                in(reg) addr,
+                options(nostack, readonly, preserves_flags)
+            ) // Write-prefetch for L1 cache
+        } else {
+            prefetch_read_data::<T, LOCALITY>(addr)
+        }
+    }
+    #[cfg(all(branches_stable, not(branches_x86_detect), not(branches_no_prefetch)))]
     {
         #[cfg(target_arch = "x86_64")]
         unsafe {
@@ -379,8 +952,25 @@ pub fn prefetch_write_data<T, const LOCALITY: i32>(addr: *const T) {
             )
         }
 
-        // `prfm` only exists on AArch64; 32-bit ARM would need `pldw`, which
-        // requires the MP extension, so arm stays a no-op.
+        // 32-bit ARM: `pldw` requires the Multiprocessing Extensions. Without
+        // them, fall back to a read prefetch like 32-bit x86 does.
+        #[cfg(all(target_arch = "arm", branches_arm_pldw))]
+        unsafe {
+            core::arch::asm!(
+                "pldw [{}]",
+                in(reg) addr,
+                options(nostack, readonly, preserves_flags)
+            );
+        }
+        #[cfg(all(target_arch = "arm", branches_arm_pld, not(branches_arm_pldw)))]
+        unsafe {
+            core::arch::asm!(
+                "pld [{}]",
+                in(reg) addr,
+                options(nostack, readonly, preserves_flags)
+            );
+        }
+
         #[cfg(target_arch = "aarch64")]
         unsafe {
             match LOCALITY {
@@ -409,7 +999,12 @@ pub fn prefetch_write_data<T, const LOCALITY: i32>(addr: *const T) {
 
         // The Zicbop extension is not part of the baseline riscv64gc target,
         // so the instruction is only emitted when the feature is enabled.
-        #[cfg(all(target_arch = "riscv64", target_feature = "zicbop"))]
+        // Without Zihintntl there is no way to express a cache level.
+        #[cfg(all(
+            target_arch = "riscv64",
+            target_feature = "zicbop",
+            not(target_feature = "zihintntl")
+        ))]
         unsafe {
             core::arch::asm!(
                 "prefetch.w 0({})",
@@ -418,6 +1013,87 @@ pub fn prefetch_write_data<T, const LOCALITY: i32>(addr: *const T) {
             );
         }
 
+        // Zihintntl hints apply to the memory access that immediately follows
+        // them, so each one shares an `asm!` block with its prefetch.
+        #[cfg(all(
+            target_arch = "riscv64",
+            target_feature = "zicbop",
+            target_feature = "zihintntl"
+        ))]
+        unsafe {
+            match LOCALITY {
+                0 => core::arch::asm!(
+                    "prefetch.w 0({})",
+                    in(reg) addr,
+                    options(nostack, readonly, preserves_flags)
+                ), // L1 cache
+                1 => core::arch::asm!(
+                    "ntl.p1",
+                    "prefetch.w 0({})",
+                    in(reg) addr,
+                    options(nostack, readonly, preserves_flags)
+                ), // L2 cache
+                2 => core::arch::asm!(
+                    "ntl.pall",
+                    "prefetch.w 0({})",
+                    in(reg) addr,
+                    options(nostack, readonly, preserves_flags)
+                ), // L3 cache
+                _ => core::arch::asm!(
+                    "ntl.all",
+                    "prefetch.w 0({})",
+                    in(reg) addr,
+                    options(nostack, readonly, preserves_flags)
+                ), // Non-temporal
+            }
+        }
+
+        // `preld` only distinguishes L1 from L3, so every request other than
+        // L1 goes to L3, which at least keeps the line out of L1.
+        #[cfg(all(target_arch = "loongarch64", rustc_ge_1_72_0))]
+        unsafe {
+            match LOCALITY {
+                0 => core::arch::asm!(
+                    "preld 8, {}, 0",
+                    in(reg) addr,
+                    options(nostack, readonly, preserves_flags)
+                ), // L1 cache
+                _ => core::arch::asm!(
+                    "preld 10, {}, 0",
+                    in(reg) addr,
+                    options(nostack, readonly, preserves_flags)
+                ), // L3 cache
+            }
+        }
+
+        // `pfd` has no cache-level operand, so `LOCALITY` is ignored. Register
+        // 0 means "no base" in an address, hence `reg_addr`.
+        #[cfg(all(target_arch = "s390x", rustc_ge_1_84_0))]
+        unsafe {
+            core::arch::asm!(
+                "pfd 2, 0({})",
+                in(reg_addr) addr,
+                options(nostack, readonly, preserves_flags)
+            );
+        }
+
+        // this requires unstable asm feature, uncomment when stabilized
+        //#[cfg(any(target_arch = "mips", target_arch = "mips64"))]
+        //unsafe {
+        //    match LOCALITY {
+        //        0..=2 => core::arch::asm!(
+        //            "pref 1, 0({})",
+        //            in(reg) addr,
+        //            options(nostack, readonly, preserves_flags)
+        //        ), // Store
+        //        _ => core::arch::asm!(
+        //            "pref 5, 0({})",
+        //            in(reg) addr,
+        //            options(nostack, readonly, preserves_flags)
+        //        ), // Store streamed
+        //    }
+        //}
+
         // this requires unstable asm feature, uncomment when stabilized
         //#[cfg(any(target_arch = "powerpc", target_arch = "powerpc64"))]
         //unsafe {
@@ -428,7 +1104,7 @@ pub fn prefetch_write_data<T, const LOCALITY: i32>(addr: *const T) {
         //    ); // Write-prefetch
         // }
     }
-    #[cfg(branches_nightly)]
+    #[cfg(all(branches_nightly, not(branches_x86_detect), not(branches_no_prefetch)))]
     {
         // `core::intrinsics` uses the opposite locality convention
         // (0 = no locality .. 3 = maximally local), so translate to keep
@@ -444,6 +1120,713 @@ pub fn prefetch_write_data<T, const LOCALITY: i32>(addr: *const T) {
     }
 }
 
+/// Writes back and evicts the cache line containing `addr`.
+///
+/// Equivalent to [`flush_range`] over a single byte.
+///
+/// # Safety
+///
+/// `addr` must point into memory mapped by the current process, like for
+/// [`flush_range`].
+#[inline(always)]
+pub unsafe fn flush_line<T>(addr: *const T) {
+    flush_range(addr as *const u8, 1)
+}
+
+/// Writes back and evicts every cache line overlapping `addr..addr + len`
+/// from the whole cache hierarchy, then waits for the flushes to complete.
+///
+/// Use it to drop data that will not be touched again, or to push it out to
+/// memory observed by something other than the CPU caches. The data itself
+/// is never modified.
+///
+/// On `x86`/`x86_64` (with the `sse` target feature), the CPU is queried
+/// once and the range is flushed with `clflushopt` followed by `sfence`
+/// when available, `clflush` otherwise. On `aarch64`, it emits
+/// `dc civac` followed by `dsb sy`, stepping by the line size reported in
+/// `CTR_EL0`. On `riscv64` with the `zicbom` target feature, it emits
+/// `cbo.flush` followed by `fence rw, rw`, assuming 64-byte cache blocks.
+/// On other targets this is a no-op.
+///
+/// # Safety
+///
+/// The whole range must be mapped by the current process: unlike
+/// prefetches, cache maintenance instructions fault on unmapped addresses.
+#[inline]
+pub unsafe fn flush_range(addr: *const u8, len: usize) {
+    let _ = (addr, len);
+    #[cfg(all(
+        any(target_arch = "x86", target_arch = "x86_64"),
+        target_feature = "sse",
+        not(branches_no_asm)
+    ))]
+    {
+        let features = x86_features();
+        if features & X86_CLFLUSHOPT != 0 {
+            for_each_line(addr, len, 64, |line| {
+                core::arch::asm!(
+                    "clflushopt [{}]",
+                    in(reg) line,
+                    options(nostack, preserves_flags)
+                )
+            });
+            core::arch::asm!("sfence", options(nostack, preserves_flags));
+        } else if features & X86_CLFLUSH != 0 {
+            // `clflush` is ordered with stores and other flushes.
+            for_each_line(addr, len, 64, |line| {
+                core::arch::asm!(
+                    "clflush [{}]",
+                    in(reg) line,
+                    options(nostack, preserves_flags)
+                )
+            });
+        }
+    }
+
+    #[cfg(all(target_arch = "aarch64", not(branches_no_asm)))]
+    {
+        for_each_line(addr, len, aarch64_dcache_line(), |line| {
+            core::arch::asm!(
+                "dc civac, {}",
+                in(reg) line,
+                options(nostack, preserves_flags)
+            )
+        });
+        core::arch::asm!("dsb sy", options(nostack, preserves_flags));
+    }
+
+    #[cfg(all(
+        target_arch = "riscv64",
+        target_feature = "zicbom",
+        not(branches_no_asm)
+    ))]
+    {
+        for_each_line(addr, len, 64, |line| {
+            core::arch::asm!(
+                "cbo.flush ({})",
+                in(reg) line,
+                options(nostack, preserves_flags)
+            )
+        });
+        core::arch::asm!("fence rw, rw", options(nostack, preserves_flags));
+    }
+}
+
+/// Writes back every dirty cache line overlapping `addr..addr + len` to
+/// memory, then waits for the write-backs to complete.
+///
+/// Unlike [`flush_range`], the lines may stay cached, so later reads can
+/// still hit. Use it to make data visible to observers that bypass the CPU
+/// caches without giving up the cached copy.
+///
+/// On `x86`/`x86_64` (with the `sse` target feature), the CPU is queried
+/// once and the range is written back with `clwb` when available, falling
+/// back to `clflushopt` and then `clflush`, which also evict the lines.
+/// Weakly ordered instructions are followed by `sfence`. On `aarch64`, it
+/// emits `dc cvac` followed by `dsb sy`, stepping by the line size reported
+/// in `CTR_EL0`. On `riscv64` with the `zicbom` target feature, it emits
+/// `cbo.clean` followed by `fence rw, rw`, assuming 64-byte cache blocks.
+/// On other targets this is a no-op.
+///
+/// # Safety
+///
+/// The whole range must be mapped by the current process: unlike
+/// prefetches, cache maintenance instructions fault on unmapped addresses.
+#[inline]
+pub unsafe fn clean_range(addr: *const u8, len: usize) {
+    let _ = (addr, len);
+    #[cfg(all(
+        any(target_arch = "x86", target_arch = "x86_64"),
+        target_feature = "sse",
+        not(branches_no_asm)
+    ))]
+    {
+        let features = x86_features();
+        if features & X86_CLWB != 0 {
+            for_each_line(addr, len, 64, |line| {
+                core::arch::asm!(
+                    "clwb [{}]",
+                    in(reg) line,
+                    options(nostack, preserves_flags)
+                )
+            });
+            core::arch::asm!("sfence", options(nostack, preserves_flags));
+        } else {
+            flush_range(addr, len);
+        }
+    }
+
+    #[cfg(all(target_arch = "aarch64", not(branches_no_asm)))]
+    {
+        for_each_line(addr, len, aarch64_dcache_line(), |line| {
+            core::arch::asm!(
+                "dc cvac, {}",
+                in(reg) line,
+                options(nostack, preserves_flags)
+            )
+        });
+        core::arch::asm!("dsb sy", options(nostack, preserves_flags));
+    }
+
+    #[cfg(all(
+        target_arch = "riscv64",
+        target_feature = "zicbom",
+        not(branches_no_asm)
+    ))]
+    {
+        for_each_line(addr, len, 64, |line| {
+            core::arch::asm!(
+                "cbo.clean ({})",
+                in(reg) line,
+                options(nostack, preserves_flags)
+            )
+        });
+        core::arch::asm!("fence rw, rw", options(nostack, preserves_flags));
+    }
+}
+
+/// Makes the bytes in `addr..addr + len` durable: writes every cache line
+/// overlapping the range back to the persistence domain, then waits for the
+/// write-backs to complete.
+///
+/// Intended for persistent memory mapped with DAX, where a store is only
+/// durable once it has left the CPU caches. On ordinary memory it behaves
+/// like [`clean_range`].
+///
+/// On `x86`/`x86_64` (with the `sse` target feature), the CPU is queried
+/// once and the range is written back with `clwb`, `clflushopt` or
+/// `clflush`, in that order of preference, followed by `sfence`. On
+/// `aarch64`, it emits `dc cvap` (clean to the point of persistence)
+/// followed by `dsb sy` when the CPU supports it, which is known at compile
+/// time with the `dpb` target feature and detected at runtime with the
+/// `std` feature on rustc 1.60 or newer. Otherwise it cleans to the point of
+/// coherency with `dc cvac`, which is the persistence point on systems
+/// without `dc cvap`. On `riscv64` with the `zicbom` target feature, it
+/// emits `cbo.clean` followed by `fence rw, rw`. On other targets this is a
+/// no-op.
+///
+/// # Safety
+///
+/// The whole range must be mapped by the current process: unlike
+/// prefetches, cache maintenance instructions fault on unmapped addresses.
+#[inline]
+// `is_aarch64_feature_detected!` is only used on rustc >= 1.60.
+#[allow(clippy::incompatible_msrv)]
+pub unsafe fn persist_range(addr: *const u8, len: usize) {
+    #[cfg(all(target_arch = "aarch64", not(branches_no_asm)))]
+    {
+        #[cfg(target_feature = "dpb")]
+        let dpb = true;
+        #[cfg(all(not(target_feature = "dpb"), feature = "std", rustc_ge_1_60_0))]
+        let dpb = std::arch::is_aarch64_feature_detected!("dpb");
+        #[cfg(all(
+            not(target_feature = "dpb"),
+            not(all(feature = "std", rustc_ge_1_60_0))
+        ))]
+        let dpb = false;
+
+        if dpb {
+            // `dc cvap` spelled as the `sys` instruction it aliases, which
+            // assembles without enabling the `dpb` target feature.
+            for_each_line(addr, len, aarch64_dcache_line(), |line| {
+                core::arch::asm!(
+                    "sys #3, c7, c12, #1, {}",
+                    in(reg) line,
+                    options(nostack, preserves_flags)
+                )
+            });
+            core::arch::asm!("dsb sy", options(nostack, preserves_flags));
+            return;
+        }
+    }
+
+    clean_range(addr, len);
+    // `clean_range` only fences weakly ordered write-backs, but durability
+    // must not depend on `clflush` ordering alone.
+    #[cfg(all(
+        any(target_arch = "x86", target_arch = "x86_64"),
+        target_feature = "sse",
+        not(branches_no_asm)
+    ))]
+    core::arch::asm!("sfence", options(nostack, preserves_flags));
+}
+
+/// Makes freshly written instructions in `addr..addr + len` visible to
+/// instruction fetch, e.g. after a JIT compiler emitted machine code there.
+///
+/// Call it after writing the code and before jumping to it.
+///
+/// On `aarch64`, it performs the architectural sequence: `dc cvau` over the
+/// range, `dsb ish`, `ic ivau` over the range, `dsb ish` and `isb`, stepping
+/// by the line sizes reported in `CTR_EL0` and skipping the data or
+/// instruction cache half when `CTR_EL0.IDC`/`CTR_EL0.DIC` report it as
+/// unnecessary. On `riscv32`/`riscv64` with the `zifencei` target feature,
+/// it emits `fence.i`, which only synchronizes the calling hart: code that
+/// runs on other harts also needs the OS facility for it, such as the
+/// `riscv_flush_icache` system call on Linux. On `x86`/`x86_64` instruction
+/// caches are coherent, so this is a no-op, as it is on other targets, which
+/// may need an OS facility instead.
+///
+/// # Safety
+///
+/// The whole range must be mapped by the current process: unlike
+/// prefetches, cache maintenance instructions fault on unmapped addresses.
+#[inline]
+pub unsafe fn sync_icache(addr: *const u8, len: usize) {
+    let _ = (addr, len);
+    #[cfg(all(target_arch = "aarch64", not(branches_no_asm)))]
+    {
+        let ctr = aarch64_ctr_el0();
+        // IDC: cleaning to the point of unification is not required.
+        if ctr & (1 << 28) == 0 {
+            for_each_line(addr, len, 4 << ((ctr >> 16) & 0xF), |line| {
+                core::arch::asm!(
+                    "dc cvau, {}",
+                    in(reg) line,
+                    options(nostack, preserves_flags)
+                )
+            });
+        }
+        core::arch::asm!("dsb ish", options(nostack, preserves_flags));
+        // DIC: invalidating the instruction cache is not required.
+        if ctr & (1 << 29) == 0 {
+            for_each_line(addr, len, 4 << (ctr & 0xF), |line| {
+                core::arch::asm!(
+                    "ic ivau, {}",
+                    in(reg) line,
+                    options(nostack, preserves_flags)
+                )
+            });
+            core::arch::asm!("dsb ish", options(nostack, preserves_flags));
+        }
+        core::arch::asm!("isb", options(nostack, preserves_flags));
+    }
+
+    #[cfg(all(
+        any(target_arch = "riscv32", target_arch = "riscv64"),
+        target_feature = "zifencei",
+        not(branches_no_asm)
+    ))]
+    core::arch::asm!("fence.i", options(nostack, preserves_flags));
+}
+
+/// Hints to the CPU that the cache line containing `addr` should be moved
+/// to a cache level shared with other cores.
+///
+/// Use it after producing data that another core will consume next, so the
+/// consumer does not have to snoop it out of the producer's private caches.
+///
+/// Like prefetching, this is only a hint and is safe to call with any
+/// pointer. It emits `cldemote` on `x86`/`x86_64`, which executes as a
+/// no-op on CPUs that predate it. On other targets this is a no-op.
+#[inline(always)]
+pub fn demote_line<T>(addr: *const T) {
+    let _ = addr;
+    #[cfg(all(any(target_arch = "x86", target_arch = "x86_64"), not(branches_no_asm)))]
+    unsafe {
+        core::arch::asm!(
+            "cldemote [{}]",
+            in(reg) addr,
+            options(nostack, readonly, preserves_flags)
+        )
+    }
+}
+
+// Calls `f` with the start of every `line`-byte cache line overlapping
+// `addr..addr + len`. `line` must be a power of two.
+#[cfg(all(
+    any(
+        all(
+            any(target_arch = "x86", target_arch = "x86_64"),
+            target_feature = "sse"
+        ),
+        target_arch = "aarch64",
+        all(
+            any(target_arch = "riscv32", target_arch = "riscv64"),
+            target_feature = "zicbom"
+        )
+    ),
+    not(branches_no_asm)
+))]
+#[inline(always)]
+unsafe fn for_each_line(addr: *const u8, len: usize, line: usize, mut f: impl FnMut(*const u8)) {
+    if len == 0 {
+        return;
+    }
+    let end = (addr as usize).saturating_add(len);
+    let mut cur = addr.wrapping_sub(addr as usize & (line - 1));
+    while (cur as usize) < end {
+        f(cur);
+        cur = cur.wrapping_add(line);
+    }
+}
+
+// Reads `CTR_EL0`, which describes the cache geometry and is readable from
+// user space on every mainstream OS.
+#[cfg(all(target_arch = "aarch64", not(branches_no_asm)))]
+#[inline(always)]
+fn aarch64_ctr_el0() -> u64 {
+    let ctr: u64;
+    unsafe {
+        core::arch::asm!(
+            "mrs {}, ctr_el0",
+            out(reg) ctr,
+            options(nomem, nostack, preserves_flags)
+        )
+    };
+    ctr
+}
+
+// Returns the smallest data cache line size in bytes, from `CTR_EL0`
+// `DminLine` (log2 of the number of 4-byte words).
+#[cfg(all(target_arch = "aarch64", not(branches_no_asm)))]
+#[inline(always)]
+fn aarch64_dcache_line() -> usize {
+    4 << ((aarch64_ctr_el0() >> 16) & 0xF)
+}
+
+// Features of the running x86 CPU, as detected by `x86_features()`. Needed
+// where using an instruction the CPU lacks would fault.
+#[cfg(all(
+    any(target_arch = "x86", target_arch = "x86_64"),
+    target_feature = "sse",
+    not(branches_no_asm)
+))]
+const X86_DETECTED: u8 = 1;
+#[cfg(all(
+    any(target_arch = "x86", target_arch = "x86_64"),
+    target_feature = "sse",
+    not(branches_no_asm)
+))]
+const X86_PRFCHW: u8 = 1 << 1;
+#[cfg(all(
+    any(target_arch = "x86", target_arch = "x86_64"),
+    target_feature = "sse",
+    not(branches_no_asm)
+))]
+const X86_PREFETCHWT1: u8 = 1 << 2;
+#[cfg(all(
+    any(target_arch = "x86", target_arch = "x86_64"),
+    target_feature = "sse",
+    not(branches_no_asm)
+))]
+const X86_CLFLUSH: u8 = 1 << 3;
+#[cfg(all(
+    any(target_arch = "x86", target_arch = "x86_64"),
+    target_feature = "sse",
+    not(branches_no_asm)
+))]
+const X86_CLFLUSHOPT: u8 = 1 << 4;
+#[cfg(all(
+    any(target_arch = "x86", target_arch = "x86_64"),
+    target_feature = "sse",
+    not(branches_no_asm)
+))]
+const X86_CLWB: u8 = 1 << 5;
+
+#[cfg(all(
+    any(target_arch = "x86", target_arch = "x86_64"),
+    target_feature = "sse",
+    not(branches_no_asm)
+))]
+static X86_FEATURES: core::sync::atomic::AtomicU8 = core::sync::atomic::AtomicU8::new(0);
+
+// Returns the cached `X86_*` feature bits, running CPUID on first use.
+// Racing threads may both detect, but they always store the same value.
+#[cfg(all(
+    any(target_arch = "x86", target_arch = "x86_64"),
+    target_feature = "sse",
+    not(branches_no_asm)
+))]
+#[inline(always)]
+fn x86_features() -> u8 {
+    use core::sync::atomic::Ordering;
+
+    #[cold]
+    #[inline(never)]
+    fn detect() -> u8 {
+        #[cfg(target_arch = "x86")]
+        use core::arch::x86::{__cpuid, __cpuid_count, __get_cpuid_max};
+        #[cfg(target_arch = "x86_64")]
+        use core::arch::x86_64::{__cpuid, __cpuid_count, __get_cpuid_max};
+
+        let bit = |set: bool, feature: u8| if set { feature } else { 0 };
+        // SAFETY: every CPU with SSE has CPUID, and each leaf is only
+        // queried when the CPU reports it. The CPUID intrinsics are only
+        // safe functions on newer toolchains.
+        #[allow(unused_unsafe)]
+        let features = unsafe {
+            let mut features = X86_DETECTED;
+            // CPUID.01H:EDX[19] is CLFSH.
+            features |= bit(__cpuid(1).edx & (1 << 19) != 0, X86_CLFLUSH);
+            if __get_cpuid_max(0).0 >= 7 {
+                let leaf7 = __cpuid_count(7, 0);
+                // CPUID.(EAX=07H, ECX=0):EBX[23] is CLFLUSHOPT, EBX[24] is
+                // CLWB and ECX[0] is PREFETCHWT1.
+                features |= bit(leaf7.ebx & (1 << 23) != 0, X86_CLFLUSHOPT);
+                features |= bit(leaf7.ebx & (1 << 24) != 0, X86_CLWB);
+                features |= bit(leaf7.ecx & 1 != 0, X86_PREFETCHWT1);
+            }
+            // CPUID.80000001H:ECX[8] is PRFCHW. 3DNow! CPUs predate that bit
+            // but implement `prefetchw` as well, reported in EDX[31].
+            if __get_cpuid_max(0x8000_0000).0 >= 0x8000_0001 {
+                let ext = __cpuid(0x8000_0001);
+                features |= bit(
+                    ext.ecx & (1 << 8) != 0 || ext.edx & (1 << 31) != 0,
+                    X86_PRFCHW,
+                );
+            }
+            features
+        };
+        X86_FEATURES.store(features, Ordering::Relaxed);
+        features
+    }
+
+    match X86_FEATURES.load(Ordering::Relaxed) {
+        0 => detect(),
+        features => features,
+    }
+}
+
+/// Stores `val` to `dst` with a non-temporal (streaming) hint.
+///
+/// Streaming stores write data around the cache instead of allocating a
+/// line for it, which avoids evicting useful data when `dst` will not be
+/// read again soon, e.g. when filling a large output buffer.
+///
+/// On stable, `movnti` (4- and 8-byte values) and `movntdq` (16-byte values
+/// at a 16-byte aligned address) are emitted on `x86`/`x86_64` with the
+/// `sse2` target feature (8-byte values need `x86_64`), and `stnp` (8- and
+/// 16-byte values) on `aarch64`. Every other value is stored with a regular
+/// [`core::ptr::write`]. On nightly, the store is lowered by LLVM for every
+/// type and architecture that supports one.
+///
+/// Streaming stores are weakly ordered: they may become visible to other
+/// threads after later stores, atomics included. Call [`store_fence`] before
+/// publishing the data, e.g. before a release store of a "ready" flag.
+///
+/// # Safety
+///
+/// `dst` must be valid for writes and properly aligned, like for
+/// [`core::ptr::write`].
+#[inline(always)]
+pub unsafe fn stream_store<T: Copy>(dst: *mut T, val: T) {
+    #[cfg(any(branches_stable, branches_no_asm))]
+    {
+        #[cfg(all(
+            any(target_arch = "x86", target_arch = "x86_64"),
+            target_feature = "sse2",
+            not(branches_no_asm)
+        ))]
+        // `T` may have padding, which must not be read as an integer, so
+        // every path loads `val` from memory inside the assembly.
+        match core::mem::size_of::<T>() {
+            4 => {
+                return core::arch::asm!(
+                    "mov {tmp:e}, [{src}]",
+                    "movnti [{dst}], {tmp:e}",
+                    dst = in(reg) dst,
+                    src = in(reg) &val,
+                    tmp = out(reg) _,
+                    options(nostack, preserves_flags)
+                );
+            }
+            #[cfg(target_arch = "x86_64")]
+            8 => {
+                return core::arch::asm!(
+                    "mov {tmp}, [{src}]",
+                    "movnti [{dst}], {tmp}",
+                    dst = in(reg) dst,
+                    src = in(reg) &val,
+                    tmp = out(reg) _,
+                    options(nostack, preserves_flags)
+                );
+            }
+            // `movntdq` faults on unaligned addresses, unlike `movnti`.
+            16 if dst as usize % 16 == 0 => {
+                return core::arch::asm!(
+                    "movdqu {tmp}, [{src}]",
+                    "movntdq [{dst}], {tmp}",
+                    dst = in(reg) dst,
+                    src = in(reg) &val,
+                    tmp = out(xmm_reg) _,
+                    options(nostack, preserves_flags)
+                );
+            }
+            _ => {}
+        }
+
+        #[cfg(all(target_arch = "aarch64", not(branches_no_asm)))]
+        match core::mem::size_of::<T>() {
+            8 => {
+                return core::arch::asm!(
+                    "ldp {lo:w}, {hi:w}, [{src}]",
+                    "stnp {lo:w}, {hi:w}, [{dst}]",
+                    dst = in(reg) dst,
+                    src = in(reg) &val,
+                    lo = out(reg) _,
+                    hi = out(reg) _,
+                    options(nostack, preserves_flags)
+                );
+            }
+            16 => {
+                return core::arch::asm!(
+                    "ldp {lo}, {hi}, [{src}]",
+                    "stnp {lo}, {hi}, [{dst}]",
+                    dst = in(reg) dst,
+                    src = in(reg) &val,
+                    lo = out(reg) _,
+                    hi = out(reg) _,
+                    options(nostack, preserves_flags)
+                );
+            }
+            _ => {}
+        }
+
+        dst.write(val)
+    }
+    #[cfg(all(branches_nightly, not(branches_no_asm)))]
+    core::intrinsics::nontemporal_store(dst, val)
+}
+
+/// Copies `count` values from `src` to `dst` with non-temporal loads and
+/// stores, then calls [`store_fence`].
+///
+/// Behaves like [`core::ptr::copy_nonoverlapping`], but streams the
+/// 16-byte aligned body of `dst` past the cache so a large copy does not
+/// evict the working set. The unaligned head and tail are copied normally.
+///
+/// On stable, the body uses `movntdq` stores on `x86`/`x86_64` with the
+/// `sse2` target feature, paired with `movntdqa` loads when the `sse4.1`
+/// target feature is enabled and `src` is 16-byte aligned as well, and
+/// `ldnp`/`stnp` pairs on `aarch64`. Other targets fall back to a regular
+/// copy. On nightly, the stores are lowered by LLVM.
+///
+/// # Safety
+///
+/// Same as [`core::ptr::copy_nonoverlapping`]: `src` must be valid for
+/// reads and `dst` valid for writes of `count * size_of::<T>()` bytes, both
+/// properly aligned, and the two regions must not overlap.
+#[inline]
+pub unsafe fn stream_copy<T: Copy>(dst: *mut T, src: *const T, count: usize) {
+    let mut dst = dst as *mut u8;
+    let mut src = src as *const u8;
+    let mut len = count * core::mem::size_of::<T>();
+
+    let head = dst.align_offset(16).min(len);
+    core::ptr::copy_nonoverlapping(src, dst, head);
+    dst = dst.add(head);
+    src = src.add(head);
+    len -= head;
+
+    while len >= 16 {
+        stream_copy_16(dst, src);
+        dst = dst.add(16);
+        src = src.add(16);
+        len -= 16;
+    }
+
+    core::ptr::copy_nonoverlapping(src, dst, len);
+    store_fence();
+}
+
+// Streams 16 bytes from `src` to the 16-byte aligned `dst`.
+#[inline(always)]
+unsafe fn stream_copy_16(dst: *mut u8, src: *const u8) {
+    #[cfg(any(branches_stable, branches_no_asm))]
+    {
+        #[cfg(all(
+            any(target_arch = "x86", target_arch = "x86_64"),
+            target_feature = "sse4.1",
+            not(branches_no_asm)
+        ))]
+        if src as usize % 16 == 0 {
+            return core::arch::asm!(
+                "movntdqa {tmp}, [{src}]",
+                "movntdq [{dst}], {tmp}",
+                dst = in(reg) dst,
+                src = in(reg) src,
+                tmp = out(xmm_reg) _,
+                options(nostack, preserves_flags)
+            );
+        }
+
+        #[cfg(all(
+            any(target_arch = "x86", target_arch = "x86_64"),
+            target_feature = "sse2",
+            not(branches_no_asm)
+        ))]
+        return core::arch::asm!(
+            "movdqu {tmp}, [{src}]",
+            "movntdq [{dst}], {tmp}",
+            dst = in(reg) dst,
+            src = in(reg) src,
+            tmp = out(xmm_reg) _,
+            options(nostack, preserves_flags)
+        );
+
+        #[cfg(all(target_arch = "aarch64", not(branches_no_asm)))]
+        return core::arch::asm!(
+            "ldnp {lo}, {hi}, [{src}]",
+            "stnp {lo}, {hi}, [{dst}]",
+            dst = in(reg) dst,
+            src = in(reg) src,
+            lo = out(reg) _,
+            hi = out(reg) _,
+            options(nostack, preserves_flags)
+        );
+
+        #[allow(unreachable_code)]
+        core::ptr::copy_nonoverlapping(src, dst, 16)
+    }
+    #[cfg(all(branches_nightly, not(branches_no_asm)))]
+    {
+        // Only moved around as a whole, the alignment tells LLVM that `dst`
+        // is suitable for a 16-byte streaming store. The bytes may be
+        // padding of `T`, so they are not asserted to be initialized.
+        #[allow(dead_code)]
+        #[repr(align(16))]
+        #[derive(Clone, Copy)]
+        struct Line(core::mem::MaybeUninit<[u8; 16]>);
+
+        let line = (src as *const Line).read_unaligned();
+        core::intrinsics::nontemporal_store(dst as *mut Line, line)
+    }
+}
+
+/// Orders every preceding store, streaming stores included, before every
+/// following store.
+///
+/// Emits `sfence` on `x86`/`x86_64` with the `sse` target feature and
+/// `dmb ishst` on `aarch64`. On other targets, where [`stream_store`] and
+/// [`stream_copy`] use regular stores, this is a release fence.
+#[inline(always)]
+pub fn store_fence() {
+    #[cfg(all(
+        any(target_arch = "x86", target_arch = "x86_64"),
+        target_feature = "sse",
+        not(branches_no_asm)
+    ))]
+    unsafe {
+        core::arch::asm!("sfence", options(nostack, preserves_flags))
+    }
+    #[cfg(all(target_arch = "aarch64", not(branches_no_asm)))]
+    unsafe {
+        core::arch::asm!("dmb ishst", options(nostack, preserves_flags))
+    }
+    #[cfg(any(
+        branches_no_asm,
+        not(any(
+            all(
+                any(target_arch = "x86", target_arch = "x86_64"),
+                target_feature = "sse"
+            ),
+            target_arch = "aarch64"
+        ))
+    ))]
+    core::sync::atomic::fence(core::sync::atomic::Ordering::Release)
+}
+
 // Non-generic instantiations of every architecture-specific code path.
 // Not part of the public API: only compiled when CI passes
 // `RUSTFLAGS="--cfg branches_check_asm"`, so that plain library cross-builds
@@ -466,6 +1849,32 @@ pub fn __branches_check_asm(addr: *const u8, cond: bool) -> bool {
         prefetch_write_data::<_, 3>(addr);
         prefetch_write_data::<_, { -1 }>(addr);
     }
+    unsafe {
+        let dst = addr as *mut u8;
+        stream_store(dst as *mut u32, 0);
+        stream_store(dst as *mut u64, 0);
+        stream_store(dst as *mut [u64; 2], [0; 2]);
+        stream_copy(dst, addr, 64);
+    }
+    store_fence();
+    unsafe {
+        flush_line(addr);
+        flush_range(addr, 256);
+        clean_range(addr, 256);
+        persist_range(addr, 256);
+        sync_icache(addr, 256);
+        let buf = core::slice::from_raw_parts_mut(addr as *mut u8, 256);
+        dma::clean_for_device(buf);
+        let _ = dma::invalidate_for_cpu(buf);
+    }
+    demote_line(addr);
+    speculation_barrier();
+    let _ = index_mask_nospec(addr as usize, 64);
+    let _ = select_unpredictable(cond, addr, core::ptr::null());
+    let _ = ct_select(cond, addr as u64, 0);
+    let _ = ct_select(cond, addr as u128, 0);
+    let _ = expect_value(addr, core::ptr::null());
+    let _ = expect_with_probability(cond, true, 0.7);
     if unlikely(!cond) {
         mark_unlikely();
     }
@@ -0,0 +1,343 @@
+// Branchless selection between two values.
+
+/// Types that [`select_unpredictable`] can choose between without a branch
+/// on every toolchain.
+///
+/// Implemented for every primitive integer type, `bool` and raw pointers.
+/// This trait is sealed and cannot be implemented outside of this crate.
+pub trait Select: Copy + sealed::Sealed {
+    // Mask-based selection, used where the toolchain offers no hint.
+    #[doc(hidden)]
+    fn __select_mask(condition: bool, true_val: Self, false_val: Self) -> Self;
+}
+
+/// Types that [`ct_select`] and [`ct_swap`] can choose between in constant
+/// time.
+///
+/// Implemented for every primitive integer type and `bool`. Raw pointers are
+/// left out: the selection works on plain machine words, which cannot carry
+/// the provenance of a pointer. Select an index or an offset instead and
+/// apply it to the pointer.
+///
+/// ```compile_fail
+/// let (a, b) = (1u8, 2u8);
+/// let _ = branches::ct_select(true, &a as *const u8, &b);
+/// ```
+///
+/// This trait is sealed and cannot be implemented outside of this crate.
+pub trait CtSelect: Select {
+    // Selection hidden from the optimizer, one machine word at a time.
+    #[doc(hidden)]
+    #[inline(always)]
+    fn __ct_select(condition: bool, true_val: Self, false_val: Self) -> Self {
+        use core::mem::size_of;
+
+        let mut out = false_val;
+        let words = size_of::<Self>() / size_of::<usize>();
+        let (t, f, o) = (
+            &true_val as *const Self as *const usize,
+            &false_val as *const Self as *const usize,
+            &mut out as *mut Self as *mut usize,
+        );
+        // Only reached for primitives whose size is a multiple of the word
+        // size; smaller types override this method.
+        for i in 0..words {
+            unsafe {
+                o.add(i).write_unaligned(ct_select_word(
+                    condition,
+                    t.add(i).read_unaligned(),
+                    f.add(i).read_unaligned(),
+                ))
+            };
+        }
+        out
+    }
+}
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+macro_rules! impl_select_int {
+    ($($t:ty)*) => {$(
+        impl sealed::Sealed for $t {}
+        impl Select for $t {
+            #[inline(always)]
+            fn __select_mask(condition: bool, true_val: Self, false_val: Self) -> Self {
+                // All ones when `condition` holds, all zeros otherwise.
+                let mask = (condition as $t).wrapping_neg();
+                (true_val & mask) | (false_val & !mask)
+            }
+        }
+        impl CtSelect for $t {}
+    )*};
+}
+
+// Integers no wider than a machine word go through a single word.
+macro_rules! impl_select_int_narrow {
+    ($($t:ty)*) => {$(
+        impl sealed::Sealed for $t {}
+        impl Select for $t {
+            #[inline(always)]
+            fn __select_mask(condition: bool, true_val: Self, false_val: Self) -> Self {
+                // All ones when `condition` holds, all zeros otherwise.
+                let mask = (condition as $t).wrapping_neg();
+                (true_val & mask) | (false_val & !mask)
+            }
+        }
+        impl CtSelect for $t {
+            #[inline(always)]
+            fn __ct_select(condition: bool, true_val: Self, false_val: Self) -> Self {
+                ct_select_word(condition, true_val as usize, false_val as usize) as $t
+            }
+        }
+    )*};
+}
+
+impl_select_int_narrow!(u8 u16 u32 usize i8 i16 i32 isize);
+#[cfg(target_pointer_width = "64")]
+impl_select_int_narrow!(u64 i64);
+#[cfg(not(target_pointer_width = "64"))]
+impl_select_int!(u64 i64);
+impl_select_int!(u128 i128);
+
+impl sealed::Sealed for bool {}
+impl Select for bool {
+    #[inline(always)]
+    fn __select_mask(condition: bool, true_val: Self, false_val: Self) -> Self {
+        (condition & true_val) | (!condition & false_val)
+    }
+}
+
+impl CtSelect for bool {
+    #[inline(always)]
+    fn __ct_select(condition: bool, true_val: Self, false_val: Self) -> Self {
+        ct_select_word(condition, true_val as usize, false_val as usize) != 0
+    }
+}
+
+impl<T> sealed::Sealed for *const T {}
+impl<T> Select for *const T {
+    #[inline(always)]
+    fn __select_mask(condition: bool, true_val: Self, false_val: Self) -> Self {
+        // Masking the addresses would lose the provenance of the pointer,
+        // so index a pair instead, which compiles to a conditional move.
+        [false_val, true_val][condition as usize]
+    }
+}
+
+impl<T> sealed::Sealed for *mut T {}
+impl<T> Select for *mut T {
+    #[inline(always)]
+    fn __select_mask(condition: bool, true_val: Self, false_val: Self) -> Self {
+        // Masking the addresses would lose the provenance of the pointer,
+        // so index a pair instead, which compiles to a conditional move.
+        [false_val, true_val][condition as usize]
+    }
+}
+
+/// Returns `true_val` if `condition` is true and `false_val` otherwise,
+/// preferring a conditional move over a branch.
+///
+/// This is the opposite of [`likely`](crate::likely) and
+/// [`unlikely`](crate::unlikely): use it for conditions that are close to
+/// 50/50 or follow no pattern, where any branch prediction would often be
+/// wrong and a mispredicted branch costs more than computing both values.
+///
+/// ```rust
+/// use branches::select_unpredictable;
+///
+/// fn count_below(data: &[u32], pivot: u32) -> usize {
+///     let mut count = 0;
+///     for &v in data {
+///         count += select_unpredictable(v < pivot, 1usize, 0);
+///     }
+///     count
+/// }
+/// # assert_eq!(count_below(&[5, 1, 9, 3], 4), 2);
+/// ```
+///
+/// On rustc 1.88 or newer this forwards to `core::hint::select_unpredictable`
+/// and on nightly to the matching intrinsic, which ask LLVM to emit a
+/// conditional move. On older toolchains, integers and `bool` are combined
+/// with a bit mask derived from `condition`, which has no branch to
+/// mispredict, and pointers are picked from a pair indexed by `condition`,
+/// which keeps their provenance.
+///
+/// Like the standard library version, this is an optimization hint and not
+/// a constant-time guarantee.
+#[inline(always)]
+// `core::hint::select_unpredictable` is only called when build.rs found it.
+#[allow(clippy::incompatible_msrv)]
+pub fn select_unpredictable<T: Select>(condition: bool, true_val: T, false_val: T) -> T {
+    #[cfg(branches_nightly)]
+    {
+        core::intrinsics::select_unpredictable(condition, true_val, false_val)
+    }
+    #[cfg(all(branches_stable, branches_select_unpredictable))]
+    {
+        core::hint::select_unpredictable(condition, true_val, false_val)
+    }
+    #[cfg(all(branches_stable, not(branches_select_unpredictable)))]
+    {
+        T::__select_mask(condition, true_val, false_val)
+    }
+}
+
+/// Returns `true_val` if `condition` is true and `false_val` otherwise, in
+/// constant time.
+///
+/// Unlike [`select_unpredictable`], which only hints the compiler, the
+/// selection happens inside inline assembly that the optimizer cannot see
+/// through, so it can never be turned back into a branch or a table lookup
+/// that depends on `condition`. This makes it suitable for cryptographic
+/// code that must not leak secrets through timing.
+///
+/// ```rust
+/// use branches::ct_select;
+///
+/// // Picks the secret-dependent value without branching on the secret.
+/// fn pick(secret_bit: bool, a: u64, b: u64) -> u64 {
+///     ct_select(secret_bit, a, b)
+/// }
+/// # assert_eq!(pick(true, 1, 2), 1);
+/// # assert_eq!(pick(false, 1, 2), 2);
+/// ```
+///
+/// Each machine word is selected with `cmov` on `x86_64` and on `x86` with
+/// the `sse` target feature, and with `csel` on `aarch64`. Elsewhere, both
+/// values are combined with a bit mask that first passes through an empty
+/// `asm!` block (or a volatile read where inline assembly is not stable), so
+/// the compiler cannot know its value.
+///
+/// This protects the selection itself. Constant-time code also needs every
+/// other operation on secrets to be free of secret-dependent branches and
+/// memory accesses, and relies on the CPU executing these instructions in
+/// data-independent time.
+#[inline(always)]
+pub fn ct_select<T: CtSelect>(condition: bool, true_val: T, false_val: T) -> T {
+    T::__ct_select(condition, true_val, false_val)
+}
+
+/// Swaps `a` and `b` if `condition` is true, in constant time.
+///
+/// Both values are always read and written, whatever `condition` is. See
+/// [`ct_select`] for how the selection is kept branch-free.
+///
+/// ```rust
+/// use branches::ct_swap;
+///
+/// let (mut a, mut b) = (1u32, 2u32);
+/// ct_swap(true, &mut a, &mut b);
+/// assert_eq!((a, b), (2, 1));
+/// ct_swap(false, &mut a, &mut b);
+/// assert_eq!((a, b), (2, 1));
+/// ```
+#[inline(always)]
+pub fn ct_swap<T: CtSelect>(condition: bool, a: &mut T, b: &mut T) {
+    let (old_a, old_b) = (*a, *b);
+    *a = T::__ct_select(condition, old_b, old_a);
+    *b = T::__ct_select(condition, old_a, old_b);
+}
+
+// Selects between two machine words without giving the optimizer a chance
+// to introduce a branch.
+#[inline(always)]
+fn ct_select_word(condition: bool, true_val: usize, false_val: usize) -> usize {
+    #[cfg(all(
+        any(
+            target_arch = "x86_64",
+            all(target_arch = "x86", target_feature = "sse")
+        ),
+        not(branches_no_asm)
+    ))]
+    {
+        let mut out = true_val;
+        // Every CPU with SSE has `cmov`.
+        unsafe {
+            core::arch::asm!(
+                "test {c}, {c}",
+                "cmovz {out}, {f}",
+                c = in(reg) condition as usize,
+                f = in(reg) false_val,
+                out = inout(reg) out,
+                options(pure, nomem, nostack)
+            )
+        };
+        out
+    }
+    #[cfg(all(target_arch = "aarch64", not(branches_no_asm)))]
+    {
+        let out: usize;
+        unsafe {
+            core::arch::asm!(
+                "cmp {c}, #0",
+                "csel {out}, {t}, {f}, ne",
+                c = in(reg) condition as usize,
+                t = in(reg) true_val,
+                f = in(reg) false_val,
+                out = lateout(reg) out,
+                options(pure, nomem, nostack)
+            )
+        };
+        out
+    }
+    #[cfg(any(
+        branches_no_asm,
+        not(any(
+            target_arch = "x86_64",
+            all(target_arch = "x86", target_feature = "sse"),
+            target_arch = "aarch64"
+        ))
+    ))]
+    {
+        let mask = hide(0usize.wrapping_sub(condition as usize));
+        false_val ^ ((true_val ^ false_val) & mask)
+    }
+}
+
+// Returns `value` unchanged, but opaque to the optimizer.
+#[cfg(any(
+    branches_no_asm,
+    not(any(
+        target_arch = "x86_64",
+        all(target_arch = "x86", target_feature = "sse"),
+        target_arch = "aarch64"
+    ))
+))]
+#[inline(always)]
+fn hide(mut value: usize) -> usize {
+    #[cfg(all(
+        any(
+            target_arch = "x86",
+            target_arch = "arm",
+            target_arch = "riscv32",
+            target_arch = "riscv64",
+            all(target_arch = "loongarch64", rustc_ge_1_72_0),
+            all(target_arch = "s390x", rustc_ge_1_84_0)
+        ),
+        not(branches_no_asm)
+    ))]
+    unsafe {
+        core::arch::asm!(
+            "/* {} */",
+            inout(reg) value,
+            options(pure, nomem, nostack, preserves_flags)
+        )
+    };
+    #[cfg(any(
+        branches_no_asm,
+        not(any(
+            target_arch = "x86",
+            target_arch = "arm",
+            target_arch = "riscv32",
+            target_arch = "riscv64",
+            all(target_arch = "loongarch64", rustc_ge_1_72_0),
+            all(target_arch = "s390x", rustc_ge_1_84_0)
+        ))
+    ))]
+    {
+        value = unsafe { core::ptr::read_volatile(&value) };
+    }
+    value
+}
@@ -0,0 +1,136 @@
+// Branchless kernels for sorting hot loops.
+
+use crate::select::{select_unpredictable, Select};
+
+/// Swaps `a` and `b` if `condition` is true, without a branch.
+///
+/// Both values are always read and written, so the loop running it has no
+/// data-dependent branch to mispredict. This is the building block of
+/// sorting networks and small-sort routines:
+///
+/// ```rust
+/// use branches::cond_swap;
+///
+/// // Sorts three values with a branch-free sorting network.
+/// fn sort3(v: &mut [u32; 3]) {
+///     let [a, b, c] = v;
+///     cond_swap(*b < *a, a, b);
+///     cond_swap(*c < *b, b, c);
+///     cond_swap(*b < *a, a, b);
+/// }
+/// let mut v = [3, 1, 2];
+/// sort3(&mut v);
+/// assert_eq!(v, [1, 2, 3]);
+/// ```
+///
+/// Uses [`select_unpredictable`] for both values. It is a performance tool,
+/// not a constant-time one: use [`ct_swap`](crate::ct_swap) for secrets.
+#[inline(always)]
+pub fn cond_swap<T: Select>(condition: bool, a: &mut T, b: &mut T) {
+    let (old_a, old_b) = (*a, *b);
+    *a = select_unpredictable(condition, old_b, old_a);
+    *b = select_unpredictable(condition, old_a, old_b);
+}
+
+/// Returns `(a, b)` ordered as `(min, max)`, without a branch.
+///
+/// If the values compare equal or are unordered, they are returned as given.
+///
+/// ```rust
+/// use branches::min_max;
+///
+/// assert_eq!(min_max(7, 3), (3, 7));
+/// assert_eq!(min_max(-1i64, 5), (-1, 5));
+/// ```
+#[inline(always)]
+pub fn min_max<T: Select + PartialOrd>(a: T, b: T) -> (T, T) {
+    let swap = b < a;
+    (
+        select_unpredictable(swap, b, a),
+        select_unpredictable(swap, a, b),
+    )
+}
+
+// Number of elements scanned per block, small enough for `u8` offsets.
+const BLOCK: usize = 128;
+
+/// Reorders `v` so that every element less than `pivot` comes first, and
+/// returns how many there are.
+///
+/// Elements that are not less than `pivot`, including those that are
+/// unordered with it, end up after them. The order within each side is
+/// unspecified.
+///
+/// ```rust
+/// use branches::partition_branchless;
+///
+/// let mut v = [5, 1, 8, 3, 9, 2];
+/// let mid = partition_branchless(&mut v, &4);
+/// assert_eq!(mid, 3);
+/// assert!(v[..mid].iter().all(|&x| x < 4));
+/// assert!(v[mid..].iter().all(|&x| x >= 4));
+/// ```
+///
+/// This follows the BlockQuicksort scheme: comparisons for a whole block of
+/// elements at each end are recorded first, writing the offset of each
+/// misplaced element and advancing the count with [`select_unpredictable`]
+/// on the comparison result, and the misplaced pairs are swapped
+/// afterwards. Neither loop branches on the outcome of a comparison, which
+/// makes the cost independent of how well the pivot splits the data. The
+/// remainder is finished with a branchless Lomuto pass.
+pub fn partition_branchless<T: PartialOrd>(v: &mut [T], pivot: &T) -> usize {
+    let mut l = 0;
+    let mut r = v.len();
+
+    let mut offsets_l = [0u8; BLOCK];
+    let (mut start_l, mut num_l) = (0, 0);
+    let mut offsets_r = [0u8; BLOCK];
+    let (mut start_r, mut num_r) = (0, 0);
+
+    // Both current blocks lie in `l..r` while it spans more than two blocks.
+    while r - l > 2 * BLOCK {
+        if num_l == 0 {
+            start_l = 0;
+            for (i, x) in v[l..l + BLOCK].iter().enumerate() {
+                offsets_l[num_l] = i as u8;
+                num_l += select_unpredictable(*x < *pivot, 0, 1);
+            }
+        }
+        if num_r == 0 {
+            start_r = 0;
+            for (i, x) in v[r - BLOCK..r].iter().rev().enumerate() {
+                offsets_r[num_r] = i as u8;
+                num_r += select_unpredictable(*x < *pivot, 1, 0);
+            }
+        }
+
+        let count = num_l.min(num_r);
+        for k in 0..count {
+            let left = l + offsets_l[start_l + k] as usize;
+            let right = r - 1 - offsets_r[start_r + k] as usize;
+            v.swap(left, right);
+        }
+        start_l += count;
+        num_l -= count;
+        start_r += count;
+        num_r -= count;
+
+        if num_l == 0 {
+            l += BLOCK;
+        }
+        if num_r == 0 {
+            r -= BLOCK;
+        }
+    }
+
+    // Everything before `l` is less than `pivot` and everything from `r` on
+    // is not, so only `l..r` is left to partition.
+    let rest = &mut v[l..r];
+    let mut mid = 0;
+    for i in 0..rest.len() {
+        let less = rest[i] < *pivot;
+        rest.swap(i, mid);
+        mid += select_unpredictable(less, 1, 0);
+    }
+    l + mid
+}
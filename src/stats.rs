@@ -0,0 +1,450 @@
+//! Per-call-site outcome statistics for branch hints.
+//!
+//! With the `record` feature, [`likely`](crate::likely),
+//! [`unlikely`](crate::unlikely) and [`mark_unlikely`](crate::mark_unlikely)
+//! are `#[track_caller]` and count how their call site actually behaved, as
+//! do [`expect_value`](crate::expect_value),
+//! [`expect_with_probability`](crate::expect_with_probability) and
+//! [`hint!`](crate::hint). Running a representative workload and then taking a
+//! [`snapshot`] shows which hints are wrong:
+//!
+//! ```rust
+//! use branches::{likely, stats};
+//!
+//! for i in 0..100 {
+//!     if likely(i % 10 == 0) {
+//!         // ...
+//!     }
+//! }
+//!
+//! for site in stats::snapshot() {
+//!     if site.disagrees() {
+//!         println!(
+//!             "{}:{}: {:?} hint mispredicted {:.0}% of the time",
+//!             site.location.file(),
+//!             site.location.line(),
+//!             site.hint,
+//!             site.misprediction_ratio() * 100.0
+//!         );
+//!     }
+//! }
+//! ```
+//!
+//! Counters live in a fixed-size static table updated with relaxed atomic
+//! operations, so recording never locks or allocates. Every hint pays for a
+//! table lookup and an atomic increment, which makes `record` a profiling
+//! mode: do not enable it in production builds. Once [`CAPACITY`] call sites
+//! are known, outcomes of new ones are dropped. Counters are `usize`, so
+//! they wrap on 32-bit targets after about four billion outcomes.
+//!
+//! [`write()`] exports a snapshot as JSON lines, CSV or the "collapsed" stack
+//! format of flamegraph tools. To dump the statistics when the process exits,
+//! set the `BRANCHES_STATS` environment variable to a format, optionally
+//! followed by a colon and an output file (standard error otherwise):
+//!
+//! ```bash
+//! BRANCHES_STATS=json:hints.jsonl cargo run --features branches/record
+//! BRANCHES_STATS=collapsed:hints.folded ./server && flamegraph.pl hints.folded > hints.svg
+//! ```
+//!
+//! The dump runs from an `atexit` handler on Unix and Windows, so it covers
+//! returning from `main` and [`std::process::exit`], but not aborts or fatal
+//! signals.
+
+use core::fmt;
+use core::panic::Location;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicU8, AtomicUsize, Ordering};
+use std::io::{self, Write};
+
+/// Number of distinct call sites the recorder can track.
+pub const CAPACITY: usize = 4096;
+
+/// The hint given at a call site.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Hint {
+    /// [`likely`](crate::likely): the condition was expected to be true.
+    Likely,
+    /// [`unlikely`](crate::unlikely): the condition was expected to be false.
+    Unlikely,
+    /// [`mark_unlikely`](crate::mark_unlikely): the path was expected to be
+    /// cold. Only the number of times it was reached is known.
+    Cold,
+    /// [`hint!`](crate::hint) without a profiled direction: no hint was
+    /// given, and the outcomes show which one, if any, should be.
+    Auto,
+}
+
+impl Hint {
+    /// Returns the name used for this hint in exported statistics:
+    /// `"likely"`, `"unlikely"`, `"cold"` or `"auto"`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Hint::Likely => "likely",
+            Hint::Unlikely => "unlikely",
+            Hint::Cold => "cold",
+            Hint::Auto => "auto",
+        }
+    }
+
+    fn from_u8(v: u8) -> Option<Hint> {
+        match v {
+            1 => Some(Hint::Likely),
+            2 => Some(Hint::Unlikely),
+            3 => Some(Hint::Cold),
+            4 => Some(Hint::Auto),
+            _ => None,
+        }
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            Hint::Likely => 1,
+            Hint::Unlikely => 2,
+            Hint::Cold => 3,
+            Hint::Auto => 4,
+        }
+    }
+}
+
+/// Recorded outcomes of one hinted call site.
+#[derive(Debug, Clone, Copy)]
+pub struct Site {
+    /// Source location of the hint.
+    pub location: &'static Location<'static>,
+    /// The hint given at this location.
+    pub hint: Hint,
+    /// How often the condition was true, or the path was reached for
+    /// [`Hint::Cold`].
+    pub taken: u64,
+    /// How often the condition was false. Always `0` for [`Hint::Cold`].
+    pub not_taken: u64,
+}
+
+impl Site {
+    /// Returns how often the outcome differed from the hint.
+    ///
+    /// This is `0` for [`Hint::Cold`], as reaching a cold path is not wrong
+    /// by itself. For [`Hint::Auto`], it is the count for the better of
+    /// `likely` and `unlikely`.
+    pub fn mispredicted(&self) -> u64 {
+        match self.hint {
+            Hint::Likely => self.not_taken,
+            Hint::Unlikely => self.taken,
+            Hint::Cold => 0,
+            Hint::Auto => self.taken.min(self.not_taken),
+        }
+    }
+
+    /// Returns the share of recorded outcomes that differed from the hint,
+    /// between `0.0` and `1.0`, or `0.0` if nothing was recorded.
+    pub fn misprediction_ratio(&self) -> f64 {
+        let total = self.taken + self.not_taken;
+        if total == 0 {
+            0.0
+        } else {
+            self.mispredicted() as f64 / total as f64
+        }
+    }
+
+    /// Returns `true` if the hint was wrong more often than right, meaning
+    /// it should be flipped or removed.
+    pub fn disagrees(&self) -> bool {
+        self.misprediction_ratio() > 0.5
+    }
+}
+
+struct Slot {
+    location: AtomicPtr<Location<'static>>,
+    hint: AtomicU8,
+    taken: AtomicUsize,
+    not_taken: AtomicUsize,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY: Slot = Slot {
+    location: AtomicPtr::new(ptr::null_mut()),
+    hint: AtomicU8::new(0),
+    taken: AtomicUsize::new(0),
+    not_taken: AtomicUsize::new(0),
+};
+
+static TABLE: [Slot; CAPACITY] = [EMPTY; CAPACITY];
+
+// Finds or claims the slot of `location`. Locations are compared by address
+// only, which keeps the hot path free of string comparisons; the rare
+// duplicate `Location` of one call site is merged by `snapshot`.
+fn slot(location: &'static Location<'static>, hint: Hint) -> Option<&'static Slot> {
+    let key = location as *const Location<'static> as *mut Location<'static>;
+    let hash = (key as usize >> 3).wrapping_mul(0x9E37_79B9);
+    for probe in 0..CAPACITY {
+        let slot = &TABLE[hash.wrapping_add(probe) % CAPACITY];
+        let current = slot.location.load(Ordering::Acquire);
+        if current == key {
+            return Some(slot);
+        }
+        if current.is_null() {
+            match slot.location.compare_exchange(
+                ptr::null_mut(),
+                key,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => {
+                    slot.hint.store(hint.to_u8(), Ordering::Release);
+                    register_exit_dump();
+                    return Some(slot);
+                }
+                Err(current) if current == key => return Some(slot),
+                Err(_) => {}
+            }
+        }
+    }
+    None
+}
+
+// Counts one outcome of the hint at `location`.
+#[inline(never)]
+pub(crate) fn record(location: &'static Location<'static>, hint: Hint, outcome: bool) {
+    if let Some(slot) = slot(location, hint) {
+        let counter = if outcome {
+            &slot.taken
+        } else {
+            &slot.not_taken
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Returns the recorded statistics of every hinted call site reached so far,
+/// the most mispredicted first.
+///
+/// Use [`Site::disagrees`] to find the hints that do not match the observed
+/// behavior. Outcomes recorded concurrently with the snapshot may or may not
+/// be included.
+pub fn snapshot() -> Vec<Site> {
+    let mut sites: Vec<Site> = Vec::new();
+    for slot in TABLE.iter() {
+        let location = slot.location.load(Ordering::Acquire);
+        let hint = match Hint::from_u8(slot.hint.load(Ordering::Acquire)) {
+            Some(hint) if !location.is_null() => hint,
+            _ => continue,
+        };
+        let location: &'static Location<'static> = unsafe { &*location };
+        let taken = slot.taken.load(Ordering::Relaxed) as u64;
+        let not_taken = slot.not_taken.load(Ordering::Relaxed) as u64;
+        match sites.iter_mut().find(|s| {
+            s.hint == hint
+                && s.location.line() == location.line()
+                && s.location.column() == location.column()
+                && s.location.file() == location.file()
+        }) {
+            Some(site) => {
+                site.taken += taken;
+                site.not_taken += not_taken;
+            }
+            None => sites.push(Site {
+                location,
+                hint,
+                taken,
+                not_taken,
+            }),
+        }
+    }
+    sites.sort_by(|a, b| {
+        b.mispredicted()
+            .cmp(&a.mispredicted())
+            .then_with(|| a.location.file().cmp(b.location.file()))
+            .then_with(|| a.location.line().cmp(&b.location.line()))
+            .then_with(|| a.location.column().cmp(&b.location.column()))
+    });
+    sites
+}
+
+/// Resets the counters of every call site to zero, for example to skip a
+/// warm-up phase.
+pub fn reset() {
+    for slot in TABLE.iter() {
+        slot.taken.store(0, Ordering::Relaxed);
+        slot.not_taken.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Output formats for [`write()`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Format {
+    /// One JSON object per line and site, with the fields `file`, `line`,
+    /// `column`, `hint`, `taken`, `not_taken`, `mispredicted` and
+    /// `misprediction_ratio`.
+    Json,
+    /// Comma-separated values with a header line and the same columns as
+    /// [`Format::Json`].
+    Csv,
+    /// The "collapsed" stack format read by `flamegraph.pl` and `inferno`:
+    /// `file;file:line:column hint;mispredicted count` and the matching
+    /// `correct` line, so the mispredicted share of each file and site can
+    /// be seen at a glance. Semicolons in file names are replaced with `_`.
+    Collapsed,
+}
+
+impl Format {
+    /// Returns the format named `name`: `"json"`, `"csv"` or `"collapsed"`.
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "json" => Some(Format::Json),
+            "csv" => Some(Format::Csv),
+            "collapsed" => Some(Format::Collapsed),
+            _ => None,
+        }
+    }
+}
+
+/// Writes `sites`, usually from [`snapshot`], to `out` in the given format.
+///
+/// ```rust
+/// use branches::stats::{self, Format};
+///
+/// let mut csv = Vec::new();
+/// stats::write(&stats::snapshot(), Format::Csv, &mut csv).unwrap();
+/// assert!(csv.starts_with(b"file,line,column,hint,"));
+/// ```
+pub fn write<W: Write>(sites: &[Site], format: Format, mut out: W) -> io::Result<()> {
+    if format == Format::Csv {
+        writeln!(
+            out,
+            "file,line,column,hint,taken,not_taken,mispredicted,misprediction_ratio"
+        )?;
+    }
+    for site in sites {
+        let location = site.location;
+        match format {
+            Format::Json => writeln!(
+                out,
+                "{{\"file\":\"{}\",\"line\":{},\"column\":{},\"hint\":\"{}\",\"taken\":{},\"not_taken\":{},\"mispredicted\":{},\"misprediction_ratio\":{}}}",
+                JsonEscaped(location.file()),
+                location.line(),
+                location.column(),
+                site.hint.as_str(),
+                site.taken,
+                site.not_taken,
+                site.mispredicted(),
+                site.misprediction_ratio()
+            )?,
+            Format::Csv => writeln!(
+                out,
+                "{},{},{},{},{},{},{},{}",
+                CsvEscaped(location.file()),
+                location.line(),
+                location.column(),
+                site.hint.as_str(),
+                site.taken,
+                site.not_taken,
+                site.mispredicted(),
+                site.misprediction_ratio()
+            )?,
+            Format::Collapsed => {
+                let file = location.file().replace(';', "_");
+                let total = site.taken + site.not_taken;
+                let counts = [
+                    ("mispredicted", site.mispredicted()),
+                    ("correct", total - site.mispredicted()),
+                ];
+                for &(outcome, count) in counts.iter().filter(|c| c.1 > 0) {
+                    writeln!(
+                        out,
+                        "{};{}:{}:{} {};{} {}",
+                        file,
+                        file,
+                        location.line(),
+                        location.column(),
+                        site.hint.as_str(),
+                        outcome,
+                        count
+                    )?;
+                }
+            }
+        }
+    }
+    out.flush()
+}
+
+// Formats a string as the inside of a JSON string literal.
+struct JsonEscaped<'a>(&'a str);
+
+impl fmt::Display for JsonEscaped<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for c in self.0.chars() {
+            match c {
+                '"' => f.write_str("\\\"")?,
+                '\\' => f.write_str("\\\\")?,
+                '\n' => f.write_str("\\n")?,
+                '\r' => f.write_str("\\r")?,
+                '\t' => f.write_str("\\t")?,
+                c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+                c => write!(f, "{}", c)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+// Formats a string as a CSV field, quoted only when needed.
+struct CsvEscaped<'a>(&'a str);
+
+impl fmt::Display for CsvEscaped<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.contains(&[',', '"', '\n', '\r'][..]) {
+            write!(f, "\"{}\"", self.0.replace('"', "\"\""))
+        } else {
+            f.write_str(self.0)
+        }
+    }
+}
+
+// Name of the environment variable that requests a dump at exit.
+const DUMP_VAR: &str = "BRANCHES_STATS";
+
+// Registers `dump_at_exit` the first time a call site is recorded.
+fn register_exit_dump() {
+    // Miri does not support `atexit`.
+    #[cfg(all(any(unix, windows), not(miri)))]
+    {
+        static REGISTER: std::sync::Once = std::sync::Once::new();
+        REGISTER.call_once(|| {
+            extern "C" {
+                fn atexit(callback: extern "C" fn()) -> std::os::raw::c_int;
+            }
+            extern "C" fn dump_at_exit() {
+                dump_from_env();
+            }
+            unsafe { atexit(dump_at_exit) };
+        });
+    }
+}
+
+// Writes a snapshot as requested by `BRANCHES_STATS`, if it is set.
+fn dump_from_env() {
+    let value = match std::env::var(DUMP_VAR) {
+        Ok(value) if !value.is_empty() => value,
+        _ => return,
+    };
+    let mut parts = value.splitn(2, ':');
+    let name = parts.next().unwrap_or_default();
+    let format = match Format::from_name(name) {
+        Some(format) => format,
+        None => {
+            eprintln!(
+                "branches: unknown {} format {:?}, expected json, csv or collapsed",
+                DUMP_VAR, name
+            );
+            return;
+        }
+    };
+    let result = match parts.next() {
+        Some(path) => std::fs::File::create(path).and_then(|file| write(&snapshot(), format, file)),
+        None => write(&snapshot(), format, io::stderr().lock()),
+    };
+    if let Err(err) = result {
+        eprintln!("branches: failed to write hint statistics: {}", err);
+    }
+}
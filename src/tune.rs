@@ -0,0 +1,274 @@
+// Empirical tuning of prefetch distance and locality.
+
+use crate::{prefetch_read_data, prefetch_write_data};
+use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Number of timed runs of each combination, of which the fastest counts.
+const ROUNDS: usize = 3;
+
+/// How far ahead and into which cache level a kernel prefetches.
+///
+/// [`tune_distance`] passes one to each run of the kernel and returns the
+/// fastest, and [`TuningFile`] stores them between runs of the program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Tuning {
+    /// How many elements (or iterations) ahead to prefetch.
+    pub distance: usize,
+    /// The `LOCALITY` to prefetch with: `0` = L1, `1` = L2, `2` = L3, any
+    /// other value = non-temporal.
+    pub locality: i32,
+}
+
+impl Tuning {
+    /// Calls [`prefetch_read_data`] with [`locality`](Tuning::locality).
+    ///
+    /// The locality is only known at runtime, so this costs a well
+    /// predicted branch on top of the prefetch.
+    #[inline(always)]
+    pub fn prefetch_read<T>(&self, addr: *const T) {
+        match self.locality {
+            0 => prefetch_read_data::<T, 0>(addr),
+            1 => prefetch_read_data::<T, 1>(addr),
+            2 => prefetch_read_data::<T, 2>(addr),
+            _ => prefetch_read_data::<T, 3>(addr),
+        }
+    }
+
+    /// Calls [`prefetch_write_data`] with [`locality`](Tuning::locality).
+    #[inline(always)]
+    pub fn prefetch_write<T>(&self, addr: *const T) {
+        match self.locality {
+            0 => prefetch_write_data::<T, 0>(addr),
+            1 => prefetch_write_data::<T, 1>(addr),
+            2 => prefetch_write_data::<T, 2>(addr),
+            _ => prefetch_write_data::<T, 3>(addr),
+        }
+    }
+}
+
+/// Times `workload` with every candidate distance at every locality and
+/// returns the fastest combination.
+///
+/// `workload` should run the kernel once over a representative input,
+/// prefetching [`distance`](Tuning::distance) elements ahead through
+/// [`Tuning::prefetch_read`] or [`Tuning::prefetch_write`]:
+///
+/// ```rust
+/// use branches::{tune_distance, Tuning};
+///
+/// fn gather(data: &[u64], indices: &[usize], tuning: Tuning) -> u64 {
+///     let mut sum = 0u64;
+///     for (k, &i) in indices.iter().enumerate() {
+///         if let Some(&ahead) = indices.get(k + tuning.distance) {
+///             tuning.prefetch_read(&data[ahead]);
+///         }
+///         sum = sum.wrapping_add(data[i]);
+///     }
+///     sum
+/// }
+///
+/// let data: Vec<u64> = (0..1 << 16).collect();
+/// let indices: Vec<usize> = (0..4096).map(|i| i * 7919 % data.len()).collect();
+/// let best = tune_distance(
+///     |tuning| {
+///         let _ = gather(&data, &indices, tuning);
+///     },
+///     &[4, 8, 16, 32],
+/// );
+/// assert!([4, 8, 16, 32].contains(&best.distance));
+/// ```
+///
+/// The workload runs once to warm up, then each combination is timed
+/// three times, interleaved so that frequency changes and background
+/// load affect all of them alike, and the fastest time of each counts. Ties
+/// go to the earlier candidate and the lower locality. Make sure the result
+/// of the kernel is used, or the optimizer may remove the work being timed.
+///
+/// # Panics
+///
+/// Panics if `candidates` is empty.
+pub fn tune_distance<F: FnMut(Tuning)>(mut workload: F, candidates: &[usize]) -> Tuning {
+    assert!(
+        !candidates.is_empty(),
+        "tune_distance() needs at least one candidate distance"
+    );
+    let mut timings: Vec<(Tuning, Duration)> = candidates
+        .iter()
+        .flat_map(|&distance| {
+            (0..4).map(move |locality| {
+                let tuning = Tuning { distance, locality };
+                (tuning, Duration::from_secs(u64::MAX))
+            })
+        })
+        .collect();
+
+    workload(timings[0].0);
+    for _ in 0..ROUNDS {
+        for (tuning, best) in timings.iter_mut() {
+            let start = Instant::now();
+            workload(*tuning);
+            *best = (*best).min(start.elapsed());
+        }
+    }
+
+    let mut best = timings[0];
+    for &timing in &timings[1..] {
+        if timing.1 < best.1 {
+            best = timing;
+        }
+    }
+    best.0
+}
+
+/// Tuned prefetch parameters of several kernels, stored as a `key=value`
+/// text file.
+///
+/// Each kernel is stored under a name of your choice as two keys:
+///
+/// ```text
+/// # Lines starting with `#` are comments.
+/// gather.distance=16
+/// gather.locality=0
+/// ```
+///
+/// Tune once, for example on the first run on a new machine, and load the
+/// values at startup afterwards:
+///
+/// ```rust,no_run
+/// use branches::{tune_distance, Tuning, TuningFile};
+///
+/// # fn kernel(_: Tuning) {}
+/// # fn main() -> std::io::Result<()> {
+/// let mut file = TuningFile::load("prefetch.conf")?;
+/// let tuning = match file.get("gather") {
+///     Some(tuning) => tuning,
+///     None => {
+///         let tuning = tune_distance(kernel, &[4, 8, 16, 32, 64]);
+///         file.set("gather", tuning);
+///         file.save("prefetch.conf")?;
+///         tuning
+///     }
+/// };
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Other keys and comments are kept as they are when the file is saved.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TuningFile {
+    // Every line as read, with `key=value` lines split. Comments and blank
+    // lines keep a `None` value.
+    lines: Vec<(String, Option<String>)>,
+}
+
+impl TuningFile {
+    /// Creates an empty file.
+    pub fn new() -> TuningFile {
+        TuningFile::default()
+    }
+
+    /// Reads the file at `path`, or returns an empty one if it does not
+    /// exist.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the file cannot be read, or with
+    /// [`io::ErrorKind::InvalidData`] if a line that is neither blank nor a
+    /// comment has no `=`.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<TuningFile> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => TuningFile::parse(&text),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(TuningFile::new()),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Parses the contents of a file.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`io::ErrorKind::InvalidData`] if a line that is neither
+    /// blank nor a comment has no `=`.
+    pub fn parse(text: &str) -> io::Result<TuningFile> {
+        let mut lines = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let trimmed = line.trim();
+            if trimmed.is_empty() || trimmed.starts_with('#') {
+                lines.push((line.to_string(), None));
+                continue;
+            }
+            let mut kv = trimmed.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some(key), Some(value)) => {
+                    lines.push((key.trim().to_string(), Some(value.trim().to_string())))
+                }
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("line {}: expected key=value, found {:?}", number + 1, line),
+                    ))
+                }
+            }
+        }
+        Ok(TuningFile { lines })
+    }
+
+    /// Returns the tuning stored under `name`, if both of its keys are
+    /// present and valid.
+    pub fn get(&self, name: &str) -> Option<Tuning> {
+        Some(Tuning {
+            distance: self.value(&format!("{}.distance", name))?.parse().ok()?,
+            locality: self.value(&format!("{}.locality", name))?.parse().ok()?,
+        })
+    }
+
+    /// Stores `tuning` under `name`, replacing any previous value.
+    pub fn set(&mut self, name: &str, tuning: Tuning) {
+        self.set_value(format!("{}.distance", name), tuning.distance.to_string());
+        self.set_value(format!("{}.locality", name), tuning.locality.to_string());
+    }
+
+    /// Writes the file to `path`, replacing it.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the file cannot be written.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        std::fs::write(path, self.to_string())
+    }
+
+    fn value(&self, key: &str) -> Option<&str> {
+        // The last assignment wins, like in a shell environment file.
+        self.lines
+            .iter()
+            .rev()
+            .find(|(k, v)| v.is_some() && k == key)
+            .and_then(|(_, v)| v.as_deref())
+    }
+
+    fn set_value(&mut self, key: String, value: String) {
+        match self
+            .lines
+            .iter_mut()
+            .rev()
+            .find(|(k, v)| v.is_some() && *k == key)
+        {
+            Some(line) => line.1 = Some(value),
+            None => self.lines.push((key, Some(value))),
+        }
+    }
+}
+
+impl core::fmt::Display for TuningFile {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for (key, value) in &self.lines {
+            match value {
+                Some(value) => writeln!(f, "{}={}", key, value)?,
+                None => writeln!(f, "{}", key)?,
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,29 @@
+use branches::{clean_range, demote_line, flush_line, flush_range};
+
+// Cache maintenance must never change the data it operates on.
+#[test]
+fn test_flush_and_clean_preserve_data() {
+    let mut buf: Vec<u8> = (0..4096u32).map(|i| (i * 31) as u8).collect();
+    let expected = buf.clone();
+    for &(start, len) in &[(0, 4096), (1, 1), (63, 2), (100, 1000), (4095, 1), (17, 0)] {
+        unsafe {
+            flush_range(buf.as_ptr().add(start), len);
+            clean_range(buf.as_ptr().add(start), len);
+        }
+        assert_eq!(buf, expected);
+    }
+    for i in (0..buf.len()).step_by(7) {
+        buf[i] = buf[i].wrapping_add(1);
+        unsafe { flush_line(&buf[i]) };
+        assert_eq!(buf[i], expected[i].wrapping_add(1));
+    }
+}
+
+#[test]
+fn test_demote_line_any_pointer() {
+    let value = 42u64;
+    demote_line(&value);
+    demote_line(core::ptr::null::<u64>());
+    demote_line((&value as *const u64).wrapping_add(1 << 20));
+    assert_eq!(value, 42);
+}
@@ -0,0 +1,48 @@
+use branches::{capabilities, AbortBehavior};
+
+#[test]
+fn test_capabilities_consistent() {
+    let caps = capabilities();
+    assert_eq!(caps.prefetch_read, caps.read_localities.contains(&true));
+    assert_eq!(caps.prefetch_write, caps.write_localities.contains(&true));
+    assert!(!caps.prefetch_instruction);
+    if !cfg!(feature = "prefetch") {
+        assert!(!caps.prefetch_read && !caps.prefetch_write);
+    }
+    if cfg!(feature = "std") {
+        assert_eq!(caps.abort, AbortBehavior::Process);
+    }
+    assert!(caps
+        .cfgs
+        .iter()
+        .all(|cfg| cfg.starts_with("rustc_ge_") || cfg.starts_with("branches_")));
+    assert_eq!(caps, capabilities());
+}
+
+#[test]
+fn test_capabilities_display() {
+    let caps = capabilities();
+    let line = caps.to_string();
+    assert!(!line.contains('\n'));
+    assert!(line.starts_with(&format!("backend={} ", caps.backend.as_str())));
+    assert!(line.contains(&format!(" assume={} ", caps.assume.as_str())));
+    assert!(line.contains(&format!(" abort={} ", caps.abort.as_str())));
+    for cfg in caps.cfgs {
+        assert!(line.contains(cfg));
+    }
+    if caps.read_localities == [true; 4] {
+        assert!(line.contains(" prefetch_read=L1,L2,L3,NTA "));
+    }
+    if !caps.prefetch_write {
+        assert!(line.contains(" prefetch_write=false "));
+    }
+}
+
+#[cfg(all(feature = "prefetch", target_arch = "x86_64"))]
+#[test]
+fn test_capabilities_x86_64() {
+    let caps = capabilities();
+    // Every x86_64 CPU has SSE, so read prefetches honor every locality
+    // unless they are turned off altogether.
+    assert!(caps.read_localities == [true; 4] || !caps.prefetch_read);
+}
@@ -0,0 +1,45 @@
+use branches::{ct_select, ct_swap};
+
+#[test]
+fn test_ct_select_integers() {
+    for &c in &[true, false] {
+        assert_eq!(ct_select(c, 1u8, 2), if c { 1 } else { 2 });
+        assert_eq!(ct_select(c, -1i8, 3), if c { -1 } else { 3 });
+        assert_eq!(ct_select(c, u16::MAX, 0), if c { u16::MAX } else { 0 });
+        assert_eq!(ct_select(c, -1i32, i32::MIN), if c { -1 } else { i32::MIN });
+        assert_eq!(ct_select(c, 7u64, !7), if c { 7 } else { !7 });
+        assert_eq!(ct_select(c, i64::MIN, -2), if c { i64::MIN } else { -2 });
+        assert_eq!(ct_select(c, u128::MAX, 3), if c { u128::MAX } else { 3 });
+        assert_eq!(
+            ct_select(c, 1i128 << 100, -5),
+            if c { 1 << 100 } else { -5 }
+        );
+        assert_eq!(ct_select(c, usize::MAX, 5), if c { usize::MAX } else { 5 });
+        assert_eq!(ct_select(c, true, false), c);
+        assert_eq!(ct_select(c, false, true), !c);
+    }
+}
+
+#[test]
+fn test_ct_select_index_instead_of_pointer() {
+    // Pointers are not `CtSelect`; selecting an offset keeps the provenance
+    // of the base pointer.
+    let pair = [1i32, 2];
+    for &c in &[true, false] {
+        let p = pair.as_ptr().wrapping_add(ct_select(c, 0usize, 1));
+        assert_eq!(unsafe { *p }, if c { 1 } else { 2 });
+    }
+}
+
+#[test]
+fn test_ct_swap() {
+    let (mut a, mut b) = (u128::MAX, 1u128);
+    ct_swap(false, &mut a, &mut b);
+    assert_eq!((a, b), (u128::MAX, 1));
+    ct_swap(true, &mut a, &mut b);
+    assert_eq!((a, b), (1, u128::MAX));
+
+    let (mut a, mut b) = (-3i16, 4i16);
+    ct_swap(true, &mut a, &mut b);
+    assert_eq!((a, b), (4, -3));
+}
@@ -1,4 +1,4 @@
-use branches::{assume, likely, unlikely};
+use branches::{assume, expect_value, expect_with_probability, likely, unlikely};
 use core::sync::atomic::{AtomicUsize, Ordering};
 
 #[test]
@@ -55,3 +55,26 @@ fn test_assume_in_loop_logic_preserved() {
     }
     assert_eq!(sum, (32 * 33) / 2);
 }
+
+#[test]
+fn test_expect_value_identity() {
+    for v in 0u8..=255 {
+        assert_eq!(expect_value(v, 42), v);
+    }
+    assert_eq!(expect_value("b", "a"), "b");
+    assert_eq!(expect_value(Some(1), None), Some(1));
+    assert!(expect_value(f64::NAN, f64::NAN).is_nan());
+    // Equal to the expected value, but not identical to it.
+    assert!(expect_value(-0.0f64, 0.0).is_sign_negative());
+}
+
+#[test]
+fn test_expect_with_probability_identity() {
+    for &b in &[true, false] {
+        for &expected in &[true, false] {
+            for &p in &[0.0, 0.1, 0.3, 0.5, 0.7, 0.9, 1.0, f64::NAN] {
+                assert_eq!(expect_with_probability(b, expected, p), b);
+            }
+        }
+    }
+}
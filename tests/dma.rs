@@ -0,0 +1,65 @@
+use branches::dma::{self, MisalignedBuffer};
+
+#[test]
+fn test_dma_alignment_power_of_two() {
+    assert!(dma::alignment().is_power_of_two());
+}
+
+#[test]
+fn test_dma_clean_for_device_preserves_data() {
+    let buf: Vec<u8> = (0..777u32).map(|i| i as u8).collect();
+    dma::clean_for_device(&buf);
+    dma::clean_for_device(&buf[5..9]);
+    dma::clean_for_device(&[]);
+    assert!(buf.iter().enumerate().all(|(i, &b)| b == i as u8));
+}
+
+#[test]
+fn test_dma_invalidate_rejects_misaligned() {
+    let alignment = dma::alignment();
+    if alignment == 1 {
+        return;
+    }
+    let mut storage = vec![0u8; alignment * 4];
+    let offset = storage.as_ptr().align_offset(alignment);
+    let aligned = &mut storage[offset..offset + alignment * 2];
+    let err = MisalignedBuffer { alignment };
+    assert_eq!(dma::invalidate_for_cpu(&mut aligned[1..]), Err(err));
+    assert_eq!(
+        dma::invalidate_for_cpu(&mut aligned[..alignment - 1]),
+        Err(err)
+    );
+}
+
+#[test]
+fn test_dma_invalidate_empty_is_noop() {
+    // The pointer of an empty slice is dangling and usually misaligned.
+    assert_eq!(dma::invalidate_for_cpu(&mut []), Ok(()));
+    let mut storage = [0u8; 3];
+    assert_eq!(dma::invalidate_for_cpu(&mut storage[1..1]), Ok(()));
+}
+
+// `dc ivac` is privileged on aarch64 and `cbo.inval` may be disabled for
+// user mode on riscv, so only run the cache operation where it is a no-op
+// or allowed.
+#[test]
+#[cfg(not(any(
+    target_arch = "aarch64",
+    target_arch = "riscv32",
+    target_arch = "riscv64"
+)))]
+fn test_dma_invalidate_aligned() {
+    let mut buf = vec![7u8; 4096];
+    assert_eq!(dma::invalidate_for_cpu(&mut buf), Ok(()));
+    assert!(buf.iter().all(|&b| b == 7));
+}
+
+#[test]
+#[cfg(feature = "std")]
+fn test_dma_misaligned_error_message() {
+    let err: Box<dyn std::error::Error> = Box::new(MisalignedBuffer { alignment: 64 });
+    assert_eq!(
+        err.to_string(),
+        "DMA buffer start and length must be multiples of 64 bytes"
+    );
+}
@@ -0,0 +1,29 @@
+use branches::hint;
+
+#[test]
+fn test_hint_identity() {
+    for i in 0..10 {
+        assert_eq!(hint!(i < 3), i < 3);
+    }
+}
+
+// `hint!` records at its own call site, which is what build.rs matches the
+// profile against through `file!()`, `line!()` and `column!()`.
+#[cfg(feature = "record")]
+#[test]
+fn test_hint_records_call_site() {
+    use branches::stats::{self, Hint};
+
+    let line = line!() + 2;
+    for i in 0..10 {
+        let _ = hint!(i < 3);
+    }
+    let site = stats::snapshot()
+        .into_iter()
+        .find(|s| s.location.file() == file!() && s.location.line() == line)
+        .expect("call site not recorded");
+    assert_eq!(site.hint, Hint::Auto);
+    assert_eq!(site.location.column(), 17);
+    assert_eq!((site.taken, site.not_taken), (3, 7));
+    assert_eq!(site.mispredicted(), 3);
+}
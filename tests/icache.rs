@@ -0,0 +1,68 @@
+use branches::sync_icache;
+
+#[test]
+fn test_sync_icache_data_unchanged() {
+    let buf: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+    unsafe {
+        sync_icache(buf.as_ptr(), buf.len());
+        sync_icache(buf.as_ptr().add(3), 1);
+        sync_icache(buf.as_ptr(), 0);
+    }
+    assert!(buf.iter().enumerate().all(|(i, &b)| b == i as u8));
+}
+
+// Emits a function returning a constant, runs it, then patches the constant
+// in place and checks that the new code is what runs.
+#[test]
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+fn test_sync_icache_jit_patch() {
+    const PROT_RWX: i32 = 1 | 2 | 4;
+    const MAP_PRIVATE_ANONYMOUS: i32 = 0x02 | 0x20;
+    const LEN: usize = 4096;
+
+    extern "C" {
+        fn mmap(addr: *mut u8, len: usize, prot: i32, flags: i32, fd: i32, off: isize) -> *mut u8;
+        fn munmap(addr: *mut u8, len: usize) -> i32;
+    }
+
+    // `mov eax, imm32; ret`
+    #[cfg(target_arch = "x86_64")]
+    fn emit(value: u16) -> Vec<u8> {
+        let [lo, hi] = value.to_le_bytes();
+        vec![0xB8, lo, hi, 0, 0, 0xC3]
+    }
+    // `mov w0, #imm16; ret`
+    #[cfg(target_arch = "aarch64")]
+    fn emit(value: u16) -> Vec<u8> {
+        let mov = 0x5280_0000u32 | (u32::from(value) << 5);
+        let mut code = mov.to_le_bytes().to_vec();
+        code.extend_from_slice(&0xD65F_03C0u32.to_le_bytes());
+        code
+    }
+
+    unsafe {
+        let map = mmap(
+            core::ptr::null_mut(),
+            LEN,
+            PROT_RWX,
+            MAP_PRIVATE_ANONYMOUS,
+            -1,
+            0,
+        );
+        if map as isize == -1 {
+            // W^X policies may forbid writable and executable mappings.
+            return;
+        }
+        for &value in &[42u16, 4242, 7] {
+            let code = emit(value);
+            core::ptr::copy_nonoverlapping(code.as_ptr(), map, code.len());
+            sync_icache(map, code.len());
+            let f: extern "C" fn() -> u32 = core::mem::transmute(map);
+            assert_eq!(f(), u32::from(value));
+        }
+        assert_eq!(munmap(map, LEN), 0);
+    }
+}
@@ -0,0 +1,200 @@
+// Calls every public function with valid arguments, so that
+// `cargo +nightly miri test --test miri` checks them all for undefined
+// behavior. Under Miri, inline assembly is replaced by the portable code
+// paths, prefetches are no-ops and `assume` panics on a false condition.
+//
+// `abort` is left out, since it would terminate the test process, and
+// `TuningFile` is round-tripped through text rather than the file system,
+// which Miri isolates.
+
+use branches::dma;
+use branches::{
+    assume, capabilities, clean_range, cond_swap, ct_select, ct_swap, demote_line, expect_value,
+    expect_with_probability, flush_line, flush_range, index_mask_nospec, likely, mark_unlikely,
+    min_max, partition_branchless, persist_range, select_unpredictable, speculation_barrier,
+    store_fence, stream_copy, stream_store, sync_icache, unlikely,
+};
+
+#[test]
+fn test_hints() {
+    for &b in &[true, false] {
+        assert_eq!(likely(b), b);
+        assert_eq!(unlikely(b), b);
+        assert_eq!(expect_with_probability(b, true, 0.99), b);
+        assert_eq!(expect_with_probability(b, false, 0.5), b);
+        assert_eq!(branches::hint!(b), b);
+    }
+    assert_eq!(expect_value(3u8, 3), 3);
+    assert_eq!(expect_value(4u8, 3), 4);
+    mark_unlikely();
+}
+
+#[test]
+fn test_assume() {
+    unsafe { assume(true) };
+}
+
+#[cfg(miri)]
+#[test]
+#[should_panic(expected = "branches::assume() called with a false condition")]
+fn test_assume_false_is_checked() {
+    unsafe { assume(false) };
+}
+
+#[test]
+fn test_select() {
+    for &b in &[true, false] {
+        let expected = if b { 1 } else { 2 };
+        assert_eq!(select_unpredictable(b, 1u32, 2), expected);
+        assert_eq!(ct_select(b, 1u64, 2), expected as u64);
+        assert_eq!(ct_select(b, 1u128, 2), expected as u128);
+        assert_eq!(ct_select(b, 1i8, 2), expected as i8);
+        assert!(ct_select(b, b, !b));
+
+        let (x, y) = (1u16, 2u16);
+        let p = select_unpredictable(b, &x as *const u16, &y);
+        assert_eq!(unsafe { *p }, if b { 1 } else { 2 });
+
+        let (mut c, mut d) = (1usize, 2usize);
+        ct_swap(b, &mut c, &mut d);
+        assert_eq!((c, d), if b { (2, 1) } else { (1, 2) });
+        cond_swap(b, &mut c, &mut d);
+        assert_eq!((c, d), (1, 2));
+    }
+    assert_eq!(min_max(7i32, -3), (-3, 7));
+}
+
+#[test]
+fn test_partition_branchless() {
+    // Long enough to go through the block loop before the Lomuto pass.
+    let mut v: Vec<u32> = (0..600u32)
+        .map(|i| i.wrapping_mul(2_654_435_761) % 97)
+        .collect();
+    let mid = partition_branchless(&mut v, &48);
+    assert!(v[..mid].iter().all(|&x| x < 48));
+    assert!(v[mid..].iter().all(|&x| x >= 48));
+}
+
+#[test]
+fn test_speculation() {
+    speculation_barrier();
+    let table = [10u8, 20, 30];
+    for idx in 0..5 {
+        let masked = index_mask_nospec(idx, table.len());
+        assert_eq!(masked, if idx < table.len() { idx } else { 0 });
+        assert!(table.get(masked).is_some());
+    }
+}
+
+#[cfg(feature = "prefetch")]
+#[test]
+fn test_prefetch() {
+    use branches::{prefetch_read_data, prefetch_write_data};
+
+    let data = [0u64; 16];
+    prefetch_read_data::<_, 0>(data.as_ptr());
+    prefetch_read_data::<_, 3>(data.as_ptr());
+    prefetch_write_data::<_, 1>(data.as_ptr());
+    prefetch_write_data::<_, 2>(data.as_ptr());
+    // Prefetching is allowed on any pointer.
+    prefetch_read_data::<_, 0>(core::ptr::null::<u64>());
+    prefetch_write_data::<_, 0>(data.as_ptr().wrapping_add(1000));
+}
+
+#[cfg(all(feature = "std", feature = "prefetch"))]
+#[test]
+fn test_tune() {
+    use branches::{tune_distance, TuningFile};
+
+    let data = [1u32; 64];
+    let best = tune_distance(
+        |tuning| {
+            let mut sum = 0u32;
+            for (i, &x) in data.iter().enumerate() {
+                tuning.prefetch_read(data.as_ptr().wrapping_add(i + tuning.distance));
+                tuning.prefetch_write(data.as_ptr().wrapping_add(i + tuning.distance));
+                sum += x;
+            }
+            assert_eq!(sum, 64);
+        },
+        &[2, 4],
+    );
+    assert!(best.distance == 2 || best.distance == 4);
+
+    let mut file = TuningFile::parse("# comment\nscan.distance=8\n").unwrap();
+    file.set("scan", best);
+    let reloaded = TuningFile::parse(&file.to_string()).unwrap();
+    assert_eq!(reloaded.get("scan"), Some(best));
+    assert!(reloaded.get("other").is_none());
+}
+
+#[test]
+fn test_capabilities() {
+    let caps = capabilities();
+    if cfg!(miri) {
+        assert!(!caps.prefetch_read && !caps.prefetch_write);
+        assert_eq!(caps.assume, branches::AssumeBehavior::Check);
+        assert!(caps.cfgs.contains(&"branches_no_asm"));
+    }
+    assert!(!caps.to_string().is_empty());
+}
+
+#[test]
+fn test_cache_maintenance() {
+    let mut buf = [7u8; 256];
+    unsafe {
+        flush_line(buf.as_ptr());
+        flush_range(buf.as_ptr(), buf.len());
+        clean_range(buf.as_ptr(), buf.len());
+        persist_range(buf.as_ptr(), buf.len());
+        sync_icache(buf.as_ptr(), buf.len());
+        flush_range(buf.as_ptr(), 0);
+    }
+    demote_line(buf.as_ptr());
+    buf[0] = 1;
+    assert_eq!(buf[0], 1);
+    assert!(buf[1..].iter().all(|&b| b == 7));
+}
+
+#[test]
+fn test_dma() {
+    let alignment = dma::alignment();
+    assert!(alignment.is_power_of_two());
+    let mut buf = vec![0u8; alignment * 4];
+    let start = buf.as_ptr().align_offset(alignment);
+    let aligned = &mut buf[start..start + alignment];
+    dma::clean_for_device(aligned);
+    assert!(dma::invalidate_for_cpu(aligned).is_ok());
+}
+
+#[test]
+fn test_stream() {
+    let mut words = [0u64; 4];
+    let mut lines = [[0u8; 16]; 2];
+    unsafe {
+        stream_store(&mut words[1], 5);
+        stream_store(&mut lines[1], [9u8; 16]);
+    }
+    store_fence();
+    assert_eq!(words, [0, 5, 0, 0]);
+    assert_eq!(lines, [[0; 16], [9; 16]]);
+
+    // Unaligned head and tail around a streamed body.
+    let src: Vec<u8> = (0..100).collect();
+    let mut dst = [0u8; 101];
+    unsafe { stream_copy(dst[1..].as_mut_ptr(), src.as_ptr(), src.len()) };
+    assert_eq!(&dst[1..], &src[..]);
+}
+
+#[cfg(feature = "record")]
+#[test]
+fn test_stats() {
+    use branches::stats::{self, Format};
+
+    let _ = likely(true);
+    let sites = stats::snapshot();
+    assert!(!sites.is_empty());
+    let mut out = Vec::new();
+    stats::write(&sites, Format::Json, &mut out).unwrap();
+    assert!(!out.is_empty());
+}
@@ -83,3 +83,26 @@ fn test_prefetch_multiple_addresses() {
     let checksum: u32 = big.iter().map(|&b| b as u32).sum();
     assert_eq!(checksum, (0u32..256).sum());
 }
+
+// With the `detect` feature the first write prefetch runs CPU detection;
+// concurrent first calls must all complete and agree.
+#[test]
+#[cfg(feature = "prefetch")]
+fn test_prefetch_write_concurrent_first_use() {
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            std::thread::spawn(|| {
+                let buf = [3u8; 16];
+                for _ in 0..1000 {
+                    prefetch_write_data::<_, 0>(buf.as_ptr());
+                    prefetch_write_data::<_, 2>(buf.as_ptr());
+                    prefetch_write_data::<_, 3>(buf.as_ptr());
+                }
+                buf.iter().map(|&b| b as u32).sum::<u32>()
+            })
+        })
+        .collect();
+    for handle in handles {
+        assert_eq!(handle.join().unwrap(), 48);
+    }
+}
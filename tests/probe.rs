@@ -0,0 +1,49 @@
+#![cfg(all(feature = "std", feature = "prefetch"))]
+
+use std::process::Command;
+
+fn probe(args: &[&str]) -> std::process::Output {
+    Command::new(env!("CARGO_BIN_EXE_branches-probe"))
+        .args(args)
+        .output()
+        .expect("failed to run branches-probe")
+}
+
+#[test]
+fn test_probe_json() {
+    let output = probe(&["--quick", "--json"]);
+    assert!(output.status.success());
+    let json = String::from_utf8(output.stdout).unwrap();
+    assert!(json.starts_with("{\"capabilities\":{\"backend\":"));
+    assert!(json.ends_with("}\n"));
+    assert_eq!(json.lines().count(), 1);
+    for key in &[
+        "\"caches\":[",
+        "\"latency\":[",
+        "\"level\":\"memory\"",
+        "\"prefetch\":{\"distance\":",
+        "\"locality\":3",
+    ] {
+        assert!(json.contains(key), "missing {} in {}", key, json);
+    }
+    assert_eq!(json.matches('{').count(), json.matches('}').count());
+    assert_eq!(json.matches('[').count(), json.matches(']').count());
+}
+
+#[test]
+fn test_probe_human() {
+    let output = probe(&["--quick"]);
+    assert!(output.status.success());
+    let report = String::from_utf8(output.stdout).unwrap();
+    assert!(report.starts_with("capabilities: backend="));
+    assert!(report.contains("\nload latency:\n"));
+    assert!(report.contains("LOCALITY=0"));
+}
+
+#[test]
+fn test_probe_usage() {
+    assert!(probe(&["--help"]).status.success());
+    let output = probe(&["--bogus"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("usage: branches-probe"));
+}
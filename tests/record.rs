@@ -0,0 +1,163 @@
+#![cfg(feature = "record")]
+use branches::stats::{self, Format, Hint, Site};
+use branches::{likely, mark_unlikely, unlikely};
+
+fn site_at(line: u32) -> Site {
+    stats::snapshot()
+        .into_iter()
+        .find(|s| s.location.file() == file!() && s.location.line() == line)
+        .expect("call site not recorded")
+}
+
+#[test]
+fn test_record_likely_and_unlikely() {
+    let mut lines = [0; 2];
+    for i in 0..100 {
+        lines[0] = line!() + 1;
+        let _ = likely(i % 4 == 0);
+        lines[1] = line!() + 1;
+        let _ = unlikely(i % 4 == 0);
+    }
+
+    let site = site_at(lines[0]);
+    assert_eq!(site.hint, Hint::Likely);
+    assert_eq!((site.taken, site.not_taken), (25, 75));
+    assert_eq!(site.mispredicted(), 75);
+    assert!(site.disagrees());
+
+    let site = site_at(lines[1]);
+    assert_eq!(site.hint, Hint::Unlikely);
+    assert_eq!((site.taken, site.not_taken), (25, 75));
+    assert_eq!(site.misprediction_ratio(), 0.25);
+    assert!(!site.disagrees());
+}
+
+#[test]
+fn test_record_mark_unlikely() {
+    let mut line = 0;
+    for _ in 0..3 {
+        line = line!() + 1;
+        mark_unlikely();
+    }
+    let site = site_at(line);
+    assert_eq!(site.hint, Hint::Cold);
+    assert_eq!((site.taken, site.not_taken), (3, 0));
+    assert!(!site.disagrees());
+}
+
+#[test]
+fn test_record_concurrent() {
+    let line = line!() + 5;
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            std::thread::spawn(|| {
+                for _ in 0..1000 {
+                    let _ = likely(true);
+                }
+            })
+        })
+        .collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+    let site = site_at(line);
+    assert_eq!((site.taken, site.not_taken), (4000, 0));
+}
+
+fn sample_site() -> Site {
+    Site {
+        location: std::panic::Location::caller(),
+        hint: Hint::Likely,
+        taken: 1,
+        not_taken: 3,
+    }
+}
+
+fn export(site: Site, format: Format) -> String {
+    let mut out = Vec::new();
+    stats::write(&[site], format, &mut out).unwrap();
+    String::from_utf8(out).unwrap()
+}
+
+#[test]
+fn test_write_json() {
+    let site = sample_site();
+    assert_eq!(
+        export(site, Format::Json),
+        format!(
+            "{{\"file\":\"{}\",\"line\":{},\"column\":{},\"hint\":\"likely\",\"taken\":1,\"not_taken\":3,\"mispredicted\":3,\"misprediction_ratio\":0.75}}\n",
+            site.location.file().replace('\\', "\\\\"),
+            site.location.line(),
+            site.location.column()
+        )
+    );
+}
+
+#[test]
+fn test_write_csv() {
+    let site = sample_site();
+    assert_eq!(
+        export(site, Format::Csv),
+        format!(
+            "file,line,column,hint,taken,not_taken,mispredicted,misprediction_ratio\n{},{},{},likely,1,3,3,0.75\n",
+            site.location.file(),
+            site.location.line(),
+            site.location.column()
+        )
+    );
+}
+
+#[test]
+fn test_write_collapsed() {
+    let site = sample_site();
+    let frame = format!(
+        "{};{}:{}:{} likely",
+        site.location.file(),
+        site.location.file(),
+        site.location.line(),
+        site.location.column()
+    );
+    assert_eq!(
+        export(site, Format::Collapsed),
+        format!("{0};mispredicted 3\n{0};correct 1\n", frame)
+    );
+}
+
+#[test]
+fn test_format_from_name() {
+    assert_eq!(Format::from_name("json"), Some(Format::Json));
+    assert_eq!(Format::from_name("csv"), Some(Format::Csv));
+    assert_eq!(Format::from_name("collapsed"), Some(Format::Collapsed));
+    assert_eq!(Format::from_name("xml"), None);
+}
+
+// Runs in a child process started by `test_dump_at_exit`.
+#[test]
+fn exit_dump_child() {
+    if std::env::var_os("BRANCHES_STATS").is_none() {
+        return;
+    }
+    for i in 0..10 {
+        let _ = unlikely(i < 7);
+    }
+}
+
+#[test]
+fn test_dump_at_exit() {
+    let path = std::env::temp_dir().join(format!("branches-stats-{}.csv", std::process::id()));
+    let status = std::process::Command::new(std::env::current_exe().unwrap())
+        .args(["--exact", "exit_dump_child", "--test-threads=1"])
+        .env("BRANCHES_STATS", format!("csv:{}", path.display()))
+        .stdout(std::process::Stdio::null())
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let csv = std::fs::read_to_string(&path).unwrap();
+    let _ = std::fs::remove_file(&path);
+    let line = csv
+        .lines()
+        .find(|l| l.contains(",unlikely,"))
+        .expect("site missing from dump");
+    assert!(line.ends_with(",unlikely,7,3,7,0.7"), "{}", line);
+}
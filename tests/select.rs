@@ -0,0 +1,39 @@
+use branches::select_unpredictable;
+
+#[test]
+fn test_select_unpredictable_integers() {
+    for &c in &[true, false] {
+        assert_eq!(select_unpredictable(c, 1u8, 2), if c { 1 } else { 2 });
+        assert_eq!(
+            select_unpredictable(c, u16::MAX, 0),
+            if c { u16::MAX } else { 0 }
+        );
+        assert_eq!(
+            select_unpredictable(c, -1i32, i32::MIN),
+            if c { -1 } else { i32::MIN }
+        );
+        assert_eq!(select_unpredictable(c, 7u64, !7), if c { 7 } else { !7 });
+        assert_eq!(
+            select_unpredictable(c, u128::MAX, 3),
+            if c { u128::MAX } else { 3 }
+        );
+        assert_eq!(
+            select_unpredictable(c, isize::MIN, 5),
+            if c { isize::MIN } else { 5 }
+        );
+        assert_eq!(select_unpredictable(c, true, false), c);
+    }
+}
+
+#[test]
+fn test_select_unpredictable_pointers() {
+    let mut a = 1;
+    let mut b = 2;
+    for &c in &[true, false] {
+        let p = select_unpredictable(c, &a as *const i32, &b as *const i32);
+        assert_eq!(unsafe { *p }, if c { 1 } else { 2 });
+        let p = select_unpredictable(c, &mut a as *mut i32, &mut b as *mut i32);
+        unsafe { *p += 10 };
+    }
+    assert_eq!((a, b), (11, 12));
+}
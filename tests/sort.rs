@@ -0,0 +1,63 @@
+use branches::{cond_swap, min_max, partition_branchless};
+
+// Small deterministic xorshift generator, to avoid a dependency.
+fn random_vec(len: usize, seed: u64, range: u64) -> Vec<u64> {
+    let mut state = seed | 1;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state % range
+        })
+        .collect()
+}
+
+#[test]
+fn test_cond_swap() {
+    let (mut a, mut b) = (1i32, 2i32);
+    cond_swap(false, &mut a, &mut b);
+    assert_eq!((a, b), (1, 2));
+    cond_swap(true, &mut a, &mut b);
+    assert_eq!((a, b), (2, 1));
+}
+
+#[test]
+fn test_min_max() {
+    assert_eq!(min_max(1u8, 2), (1, 2));
+    assert_eq!(min_max(2u8, 1), (1, 2));
+    assert_eq!(min_max(-5i64, -5), (-5, -5));
+    assert_eq!(min_max(u128::MAX, 0), (0, u128::MAX));
+}
+
+#[test]
+fn test_partition_branchless() {
+    for &len in &[0, 1, 2, 7, 255, 256, 257, 1000, 4099] {
+        for &range in &[1, 2, 10, 1 << 40] {
+            let original = random_vec(len, len as u64 * 31 + range, range);
+            for &pivot in &[0, 1, range / 2, range] {
+                let mut v = original.clone();
+                let mid = partition_branchless(&mut v, &pivot);
+                assert!(v[..mid].iter().all(|&x| x < pivot));
+                assert!(v[mid..].iter().all(|&x| x >= pivot));
+
+                let mut sorted = v.clone();
+                sorted.sort_unstable();
+                let mut expected = original.clone();
+                expected.sort_unstable();
+                assert_eq!(sorted, expected);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_partition_branchless_non_copy() {
+    let mut v: Vec<String> = ["pear", "apple", "fig", "kiwi", "banana"]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+    let mid = partition_branchless(&mut v, &"c".to_string());
+    assert_eq!(mid, 2);
+    assert!(v[..mid].iter().all(|s| s.as_str() < "c"));
+}
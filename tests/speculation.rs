@@ -0,0 +1,39 @@
+use branches::{index_mask_nospec, speculation_barrier};
+
+#[test]
+fn test_index_mask_nospec_clamps() {
+    for len in 0..40usize {
+        for idx in 0..50usize {
+            let expected = if idx < len { idx } else { 0 };
+            assert_eq!(
+                index_mask_nospec(idx, len),
+                expected,
+                "idx={} len={}",
+                idx,
+                len
+            );
+        }
+    }
+}
+
+#[test]
+fn test_index_mask_nospec_large_values() {
+    let max = isize::MAX as usize;
+    assert_eq!(index_mask_nospec(max - 1, max), max - 1);
+    assert_eq!(index_mask_nospec(max, max), 0);
+    assert_eq!(index_mask_nospec(0, max), 0);
+    assert_eq!(index_mask_nospec(12345, 12346), 12345);
+}
+
+#[test]
+fn test_speculation_barrier_guarded_lookup() {
+    let table = [10u8, 20, 30, 40];
+    let mut sum = 0u32;
+    for idx in 0..8 {
+        if idx < table.len() {
+            speculation_barrier();
+            sum += u32::from(table[index_mask_nospec(idx, table.len())]);
+        }
+    }
+    assert_eq!(sum, 100);
+}
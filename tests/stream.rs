@@ -0,0 +1,92 @@
+use branches::{store_fence, stream_copy, stream_store};
+
+#[test]
+fn test_stream_store_sizes() {
+    let mut a = 0u8;
+    let mut b = 0u32;
+    let mut c = 0u64;
+    let mut d = [0u64; 2];
+    let mut e = [0u8; 3];
+    unsafe {
+        stream_store(&mut a, 0xAB);
+        stream_store(&mut b, 0xDEAD_BEEF);
+        stream_store(&mut c, 0x0123_4567_89AB_CDEF);
+        stream_store(&mut d, [1, 2]);
+        stream_store(&mut e, [4, 5, 6]);
+    }
+    store_fence();
+    assert_eq!(a, 0xAB);
+    assert_eq!(b, 0xDEAD_BEEF);
+    assert_eq!(c, 0x0123_4567_89AB_CDEF);
+    assert_eq!(d, [1, 2]);
+    assert_eq!(e, [4, 5, 6]);
+}
+
+// Padding bytes are uninitialized and must be moved without being read as
+// integers.
+#[test]
+fn test_stream_store_padded() {
+    let mut a = (0u8, 0u16);
+    let mut b = (0u8, 0u32);
+    let mut c = [(0u8, 0u64); 2];
+    unsafe {
+        stream_store(&mut a, (1, 2));
+        stream_store(&mut b, (3, 4));
+        stream_store(&mut c[0], (5, 6));
+        stream_store(c[1..].as_mut_ptr(), (7, 8));
+    }
+    store_fence();
+    assert_eq!(a, (1, 2));
+    assert_eq!(b, (3, 4));
+    assert_eq!(c, [(5, 6), (7, 8)]);
+}
+
+// 16-byte values take the vector path only at 16-byte aligned addresses.
+#[test]
+fn test_stream_store_unaligned_16_bytes() {
+    let mut buf = [0u64; 5];
+    for i in 0..3 {
+        let dst = buf[i..].as_mut_ptr() as *mut [u64; 2];
+        unsafe { stream_store(dst, [i as u64 + 1, i as u64 + 2]) };
+        store_fence();
+        assert_eq!(buf[i..i + 2], [i as u64 + 1, i as u64 + 2]);
+    }
+}
+
+#[test]
+fn test_stream_copy_all_offsets_and_lengths() {
+    let src: Vec<u8> = (0..300u32).map(|i| (i * 7) as u8).collect();
+    for src_off in 0..16 {
+        for dst_off in 0..16 {
+            for &len in &[0, 1, 15, 16, 17, 31, 64, 200, 255] {
+                let mut dst = vec![0xFFu8; 300];
+                unsafe {
+                    stream_copy(
+                        dst.as_mut_ptr().add(dst_off),
+                        src.as_ptr().add(src_off),
+                        len,
+                    )
+                };
+                assert_eq!(dst[dst_off..dst_off + len], src[src_off..src_off + len]);
+                assert!(dst[..dst_off].iter().all(|&b| b == 0xFF));
+                assert!(dst[dst_off + len..].iter().all(|&b| b == 0xFF));
+            }
+        }
+    }
+}
+
+#[test]
+fn test_stream_copy_typed() {
+    let src: Vec<u64> = (0..1000).collect();
+    let mut dst = vec![0u64; 1000];
+    unsafe { stream_copy(dst.as_mut_ptr(), src.as_ptr(), src.len()) };
+    assert_eq!(dst, src);
+}
+
+#[test]
+fn test_stream_copy_padded() {
+    let src: Vec<(u8, u32)> = (0..100).map(|i| (i as u8, i)).collect();
+    let mut dst = vec![(0u8, 0u32); 100];
+    unsafe { stream_copy(dst.as_mut_ptr(), src.as_ptr(), src.len()) };
+    assert_eq!(dst, src);
+}
@@ -0,0 +1,120 @@
+#![cfg(all(feature = "std", feature = "prefetch"))]
+
+use branches::{tune_distance, Tuning, TuningFile};
+use std::io;
+use std::thread::sleep;
+use std::time::Duration;
+
+#[test]
+fn test_tune_distance_picks_fastest() {
+    // Sleeps grow by a millisecond per step away from distance 16 at L2,
+    // far more than timing noise.
+    let mut calls = 0;
+    let best = tune_distance(
+        |tuning| {
+            calls += 1;
+            let steps = (tuning.distance as i64 / 4 - 4).unsigned_abs()
+                + (tuning.locality as i64 - 1).unsigned_abs();
+            sleep(Duration::from_millis(steps));
+        },
+        &[4, 8, 16, 32],
+    );
+    assert_eq!(
+        best,
+        Tuning {
+            distance: 16,
+            locality: 1
+        }
+    );
+    // One warm-up run, then three rounds over 4 distances and 4 localities.
+    assert_eq!(calls, 1 + 3 * 4 * 4);
+}
+
+#[test]
+fn test_tune_distance_runs_kernel() {
+    let data: Vec<u64> = (0..4096).collect();
+    let mut sums = Vec::new();
+    let best = tune_distance(
+        |tuning| {
+            let mut sum = 0u64;
+            for (i, &x) in data.iter().enumerate() {
+                tuning.prefetch_read(data.as_ptr().wrapping_add(i + tuning.distance));
+                tuning.prefetch_write(data.as_ptr().wrapping_add(i + tuning.distance));
+                sum += x;
+            }
+            sums.push(sum);
+        },
+        &[8],
+    );
+    assert_eq!(best.distance, 8);
+    assert!((0..4).contains(&best.locality));
+    assert!(sums.iter().all(|&sum| sum == 4095 * 4096 / 2));
+}
+
+#[test]
+#[should_panic(expected = "at least one candidate")]
+fn test_tune_distance_no_candidates() {
+    let _ = tune_distance(|_| {}, &[]);
+}
+
+#[test]
+fn test_tuning_file_parse() {
+    let file = TuningFile::parse(
+        "# tuned on host-a\n\
+         \n\
+         gather.distance = 16\n\
+         gather.locality=0\n\
+         scan.distance=64\n\
+         scan.locality=oops\n\
+         gather.distance=24\n",
+    )
+    .unwrap();
+    assert_eq!(
+        file.get("gather"),
+        Some(Tuning {
+            distance: 24,
+            locality: 0
+        })
+    );
+    assert_eq!(file.get("scan"), None);
+    assert_eq!(file.get("missing"), None);
+
+    let err = TuningFile::parse("a=1\nno equals sign\n").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert!(err.to_string().contains("line 2"));
+}
+
+#[test]
+fn test_tuning_file_set_keeps_other_lines() {
+    let mut file = TuningFile::parse("# comment\nother=x\ngather.distance=8\n").unwrap();
+    let tuning = Tuning {
+        distance: 32,
+        locality: 2,
+    };
+    file.set("gather", tuning);
+    assert_eq!(file.get("gather"), Some(tuning));
+    assert_eq!(
+        file.to_string(),
+        "# comment\nother=x\ngather.distance=32\ngather.locality=2\n"
+    );
+}
+
+#[test]
+fn test_tuning_file_round_trip() {
+    let path = std::env::temp_dir().join(format!("branches-tune-{}.conf", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+
+    let mut file = TuningFile::load(&path).unwrap();
+    assert_eq!(file, TuningFile::new());
+    let tuning = Tuning {
+        distance: 12,
+        locality: 3,
+    };
+    file.set("matmul", tuning);
+    file.save(&path).unwrap();
+
+    let loaded = TuningFile::load(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(loaded, file);
+    assert_eq!(loaded.get("matmul"), Some(tuning));
+}